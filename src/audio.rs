@@ -1,139 +1,703 @@
 use cpal::{traits::{DeviceTrait, HostTrait, StreamTrait}, Sample};
-use crossbeam_channel::{Receiver, Sender};
-use std::{thread, sync::Arc};
-use ringbuf::{HeapRb, Consumer, Rb};
-use hound;
-
-const LATENCY: f32 = 150.0;
+use anyhow::Context;
+use crossbeam_channel::{Receiver, Sender, TrySendError};
+use std::{fmt, thread::{self, JoinHandle}, sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}}, path::{Path, PathBuf}};
+use ringbuf::{HeapRb, Consumer, Producer};
+use crate::{
+    mix::{self, VisChannel, VisFrame},
+    onset::{Onset, OnsetDetector},
+    playlist::{Playlist, Repeat},
+    resample::{Quality, Resampler},
+    source::{MicSource, Silence, Source, SourceKind, WavSource},
+};
 
+/// How the audio thread is set up.
+pub struct Options {
+    /// Wav file or directory of wav files to play.
+    pub music: PathBuf,
+    /// Delay between microphone input and output, in milliseconds.
+    pub latency: f32,
+    pub quality: Quality,
+    /// Start out playing the microphone rather than the music.
+    pub use_mic: bool,
+    /// What plays as the music.
+    pub source: SourceKind,
+}
 
 #[derive(Debug)]
 pub enum ToAudio {
     ToggleMic,
     ToggleVisuals,
+    CycleResampleQuality,
+    CycleVisChannel,
+    NextTrack,
+    PreviousTrack,
+    ToggleShuffle,
+    CycleRepeat,
+    TogglePause,
+    /// Jump forwards, or backwards for negative values, by this many seconds.
+    Seek(f32),
+    Restart,
+    /// Set the start of the loop, then its end, then clear it.
+    CycleLoop,
 }
 
+/// Seconds of frames the visuals can fall behind by before frames are
+/// dropped.
+const VIS_BUFFER_SECONDS: f32 = 0.5;
+
 pub enum FromAudio {
-    Data(f32),
+    /// The output is about to start at `sample_rate`, and what the visuals
+    /// should show of it will arrive through `frames`.
+    Started { sample_rate: u32, frames: VisReceiver },
+    /// A beat or note started in the frames sent so far.
+    Onset(Onset),
+    Status(Status),
+}
+
+/// Something that happened to the audio that the player should hear about.
+#[derive(Debug)]
+pub enum Status {
+    Playing(PathBuf),
+    /// A track that couldn't be opened, and why.
+    Skipped(PathBuf, anyhow::Error),
+    /// Reading or seeking in the last song went wrong.
+    Broken(anyhow::Error),
+    SeekFailed,
+    /// Why there is no playlist.
+    NoSongs(anyhow::Error),
+    EndOfPlaylist,
+    Quality(Quality),
+    VisChannel(VisChannel),
+    Shuffle(bool),
+    Repeat(Repeat),
+    LoopStart,
+    Looping,
+    LoopCleared,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Playing(path) => write!(f, "playing {}", path.display()),
+            Status::Skipped(path, e) => write!(f, "skipping {}: {:#}", path.display(), e),
+            Status::Broken(e) => write!(f, "error in wav file: {:#}", e),
+            Status::SeekFailed => write!(f, "failed to seek in wav file"),
+            Status::NoSongs(e) => write!(f, "no songs to play, playing silence: {:#}", e),
+            Status::EndOfPlaylist => write!(f, "end of playlist"),
+            Status::Quality(quality) => write!(f, "resampling with {:?} quality", quality),
+            Status::VisChannel(channel) => write!(f, "visualizing {:?}", channel),
+            Status::Shuffle(on) => write!(f, "shuffle {}", if *on { "on" } else { "off" }),
+            Status::Repeat(repeat) => write!(f, "repeat {:?}", repeat),
+            Status::LoopStart => write!(f, "loop start set"),
+            Status::Looping => write!(f, "looping"),
+            Status::LoopCleared => write!(f, "loop cleared"),
+        }
+    }
+}
+
+/// How often the audio callbacks had to drop or make up samples, since they
+/// can't wait for the other threads to catch up.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// Frames the visuals had fallen too far behind to get.
+    pub frames_dropped: AtomicU64,
+    /// Key presses that came in too fast to be handled.
+    pub commands_dropped: AtomicU64,
+    /// Microphone samples recorded while the output was too far behind.
+    pub mic_overflows: AtomicU64,
+    /// Microphone samples played as silence because they hadn't been
+    /// recorded yet.
+    pub mic_underflows: AtomicU64,
+}
+
+/// Sending end of the frames for the visuals, which never waits for them to
+/// catch up.
+struct VisSender {
+    frames: Producer<VisFrame, Arc<HeapRb<VisFrame>>>,
+    counters: Arc<Counters>,
+}
+
+impl VisSender {
+    /// Passes `frame` on, or drops it if the visuals have fallen behind.
+    fn send(&mut self, frame: VisFrame) {
+        if self.frames.push(frame).is_err() {
+            self.counters.frames_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Receiving end of the frames for the visuals.
+pub struct VisReceiver {
+    frames: Consumer<VisFrame, Arc<HeapRb<VisFrame>>>,
+    counters: Arc<Counters>,
+}
+
+impl VisReceiver {
+    /// The oldest frame that hasn't been received yet.
+    pub fn recv(&mut self) -> Option<VisFrame> {
+        self.frames.pop()
+    }
+
+    /// Frames dropped so far because they didn't fit in the buffer.
+    pub fn dropped(&self) -> u64 {
+        self.counters.frames_dropped.load(Ordering::Relaxed)
+    }
+
+    /// Everything the audio thread has had to drop or make up so far.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+}
+
+/// Lock-free queue of frames from the audio thread to the visuals, holding up
+/// to `capacity` of them.
+fn vis_channel(capacity: usize, counters: Arc<Counters>) -> (VisSender, VisReceiver) {
+    let (frames_prod, frames_cons) = HeapRb::new(capacity).split();
+    (
+        VisSender { frames: frames_prod, counters: counters.clone() },
+        VisReceiver { frames: frames_cons, counters },
+    )
+}
+
+/// The running audio thread, which is stopped when this is dropped.
+pub struct AudioThread {
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Drop for AudioThread {
+    fn drop(&mut self) {
+        let _ = self.stop.try_send(());
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                eprintln!("audio thread panicked");
+            }
+        }
+    }
+}
+
+/// A source along with everything it takes to play it at the output rate,
+/// so that can be made before it reaches the output callback.
+struct Track<S: Source + ?Sized> {
+    resampler: Resampler,
+    /// Last resampled frame, in the source's channel layout.
+    frame: Vec<f32>,
+    source: Box<S>,
+}
+
+impl<S: Source + ?Sized> Track<S> {
+    /// `source` resampled to `out_rate` with `quality`.
+    fn new(source: Box<S>, quality: Quality, out_rate: u32) -> Self {
+        let (rate, channels) = (source.sample_rate(), source.channels());
+        Self {
+            resampler: Resampler::new(quality, rate, out_rate, channels),
+            frame: vec![0.0; channels as usize],
+            source,
+        }
+    }
+
+    /// Moves on to the next frame, or fills it with silence and returns
+    /// false if the source has run dry.
+    fn next_frame(&mut self) -> bool {
+        if self.resampler.next_frame(self.source.as_mut(), &mut self.frame) {
+            return true;
+        }
+        self.frame.fill(0.0);
+        false
+    }
+}
+
+/// A song opened by the loader, ready to play.
+type Song = Track<dyn Source>;
+
+/// Tracks the loader can have opened ahead of the output callback.
+const TRACK_QUEUE: usize = 4;
+
+/// What the output callback tells the loader.
+enum ToLoader {
+    /// The song that was playing has run out.
+    Finished,
+    /// A track that was replaced, to be closed here rather than on the
+    /// realtime thread.
+    Retired(Song),
+}
+
+/// The output callback's ends of the queues to and from the loader.
+struct LoaderLink {
+    commands: Consumer<ToAudio, Arc<HeapRb<ToAudio>>>,
+    tracks: Consumer<Song, Arc<HeapRb<Song>>>,
+    requests: Sender<ToLoader>,
+    status: Sender<FromAudio>,
+}
+
+/// Runs on the audio control thread and does what the output callback
+/// can't, since it mustn't wait for the disk: it opens the tracks of the
+/// playlist and hands them over ready to play. Commands from the visuals
+/// come through here too, so the playlist is handled on the spot and the
+/// rest passed on.
+struct Loader {
+    playlist: Option<Playlist>,
+    commands: Producer<ToAudio, Arc<HeapRb<ToAudio>>>,
+    tracks: Producer<Song, Arc<HeapRb<Song>>>,
+    /// Whether the microphone is playing, for the input callback.
+    use_mic: Arc<AtomicBool>,
+    status: Sender<FromAudio>,
+    counters: Arc<Counters>,
+    quality: Quality,
+    out_channels: u16,
+    out_rate: u32,
+}
+
+/// A loader for `playlist` and the ends of its queues the output callback
+/// holds on to.
+fn loader(playlist: Option<Playlist>, use_mic: Arc<AtomicBool>, status: Sender<FromAudio>, counters: Arc<Counters>, quality: Quality, out_channels: u16, out_rate: u32) -> (Loader, LoaderLink, Receiver<ToLoader>) {
+    let (commands_prod, commands_cons) = HeapRb::new(1024).split();
+    let (tracks_prod, tracks_cons) = HeapRb::new(TRACK_QUEUE).split();
+    let (requests_tx, requests_rx) = crossbeam_channel::bounded(TRACK_QUEUE * 2);
+    let loader = Loader {
+        playlist,
+        commands: commands_prod,
+        tracks: tracks_prod,
+        use_mic,
+        status: status.clone(),
+        counters,
+        quality,
+        out_channels,
+        out_rate,
+    };
+    let link = LoaderLink { commands: commands_cons, tracks: tracks_cons, requests: requests_tx, status };
+    (loader, link, requests_rx)
+}
+
+impl Loader {
+    /// Handles `commands` and what the output callback asks for, until
+    /// something is sent on `stop` or either end hangs up.
+    fn run(&mut self, commands: Receiver<ToAudio>, requests: Receiver<ToLoader>, stop: Receiver<()>) {
+        loop {
+            crossbeam_channel::select! {
+                recv(commands) -> command => match command {
+                    Ok(command) => self.command(command),
+                    Err(_) => return,
+                },
+                recv(requests) -> request => match request {
+                    Ok(request) => self.request(request),
+                    Err(_) => return,
+                },
+                recv(stop) -> _ => return,
+            }
+        }
+    }
+
+    /// Queues a synthetic source of `kind`, or else the first track of the
+    /// playlist, or silence without one.
+    fn start(&mut self, kind: SourceKind) {
+        let track = match (kind.synthetic(self.out_rate), &self.playlist) {
+            (Some(source), _) => self.track(source),
+            (None, Some(playlist)) => {
+                let first = playlist.current().to_path_buf();
+                self.open(&first)
+            }
+            (None, None) => self.silence(),
+        };
+        self.play(track);
+    }
+
+    fn command(&mut self, command: ToAudio) {
+        match command {
+            ToAudio::NextTrack | ToAudio::PreviousTrack => {
+                if let Some(playlist) = &mut self.playlist {
+                    let path = match command {
+                        ToAudio::NextTrack => playlist.next(),
+                        _ => playlist.previous(),
+                    }.to_path_buf();
+                    let track = self.open(&path);
+                    self.play(track);
+                }
+            }
+            ToAudio::ToggleShuffle => {
+                if let Some(playlist) = &mut self.playlist {
+                    let shuffle = playlist.toggle_shuffle();
+                    self.send(Status::Shuffle(shuffle));
+                }
+            }
+            ToAudio::CycleRepeat => {
+                if let Some(playlist) = &mut self.playlist {
+                    let repeat = playlist.cycle_repeat();
+                    self.send(Status::Repeat(repeat));
+                }
+            }
+            command => {
+                let toggles_mic = matches!(command, ToAudio::ToggleMic);
+                if self.commands.push(command).is_err() {
+                    self.counters.commands_dropped.fetch_add(1, Ordering::Relaxed);
+                } else if toggles_mic {
+                    self.use_mic.fetch_xor(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    fn request(&mut self, request: ToLoader) {
+        match request {
+            ToLoader::Finished => {
+                let track = match self.playlist.as_mut().and_then(Playlist::advance) {
+                    Some(path) => {
+                        let path = path.to_path_buf();
+                        self.open(&path)
+                    }
+                    None => {
+                        self.send(Status::EndOfPlaylist);
+                        self.silence()
+                    }
+                };
+                self.play(track);
+            }
+            ToLoader::Retired(mut track) => {
+                if let Some(e) = track.source.take_error() {
+                    self.send(Status::Broken(e));
+                }
+            }
+        }
+    }
+
+    /// Opens the wav file at `path`, or the first of the tracks after it in
+    /// the playlist that opens if it doesn't. Silence if none of them do.
+    fn open(&mut self, path: &Path) -> Song {
+        let mut path = path.to_path_buf();
+        let attempts = self.playlist.as_ref().map_or(1, Playlist::len);
+        for _ in 0..attempts {
+            match WavSource::open(&path) {
+                Ok(song) => {
+                    self.send(Status::Playing(path));
+                    return self.track(Box::new(song));
+                }
+                Err(e) => self.send(Status::Skipped(path.clone(), e)),
+            }
+            match &mut self.playlist {
+                Some(playlist) => path = playlist.next().to_path_buf(),
+                None => break,
+            }
+        }
+        self.silence()
+    }
+
+    fn silence(&self) -> Song {
+        self.track(Box::new(Silence::new(self.out_channels, self.out_rate)))
+    }
+
+    /// `source` ready to play, at the quality the loader started with. The
+    /// output callback switches it to its own if that has changed since.
+    fn track(&self, source: Box<dyn Source>) -> Song {
+        Track::new(source, self.quality, self.out_rate)
+    }
+
+    /// Hands `track` to the output callback, to play once it gets to it.
+    fn play(&mut self, track: Song) {
+        // the callback has fallen behind by a few tracks, and this one would
+        // only be skipped over too
+        let _ = self.tracks.push(track);
+    }
+
+    fn send(&self, status: Status) {
+        let _ = self.status.try_send(FromAudio::Status(status));
+    }
 }
 
 struct AudioData {
-    cons: Consumer<ToAudio, Arc<HeapRb<ToAudio>>>,
+    link: LoaderLink,
     send_to_gfx: bool,
     use_mic: bool,
-    wav_src: hound::WavIntoSamples<std::io::BufReader<std::fs::File>,f32>,
+    file: Song,
+    /// A replaced song the loader had no room for yet, kept until it has,
+    /// so it isn't closed here.
+    retired: Option<Song>,
+    /// Whether the loader has been told that `file` ran out, and its next
+    /// track hasn't arrived yet.
+    waiting: bool,
+    mic: Track<MicSource>,
+    paused: bool,
+    /// Song frames to jump back to and from when looping.
+    loop_start: Option<u64>,
+    loop_end: Option<u64>,
+    quality: Quality,
+    vis_channel: VisChannel,
+    /// Looks for beats in what is sent to the visuals.
+    onsets: OnsetDetector,
+    /// The current frame mapped onto the output device's channels.
+    out_frame: Vec<f32>,
 }
 
 impl AudioData {
-    fn new(cons: Consumer<ToAudio, Arc<HeapRb<ToAudio>>>) -> Self {
-        // find first wav file in ./music
-        let fname = std::fs::read_dir("./music").expect("no music dir")
-            .filter_map(Result::ok)
-            .filter(|x| x.path().to_string_lossy().ends_with("wav"))
-            .next().expect("find a wav file")
-            .path();
-        let reader = hound::WavReader::open(fname).expect("open wav file");
-        let spec = reader.spec();
-        eprintln!("{:?}", spec);
-        Self { 
-            cons,
+    fn new(link: LoaderLink, mic: MicSource, use_mic: bool, quality: Quality, out_rate: u32, out_channels: u16) -> Self {
+        let silence: Box<dyn Source> = Box::new(Silence::new(out_channels, out_rate));
+        Self {
+            link,
             send_to_gfx: true,
-            use_mic: false,
-            wav_src: reader.into_samples(),
+            use_mic,
+            vis_channel: VisChannel::default(),
+            onsets: OnsetDetector::new(out_rate),
+            out_frame: vec![0.0; out_channels as usize],
+            file: Track::new(silence, quality, out_rate),
+            retired: None,
+            waiting: false,
+            mic: Track::new(Box::new(mic), quality, out_rate),
+            paused: false,
+            loop_start: None,
+            loop_end: None,
+            quality,
+        }
+    }
+
+    /// Switches to the tracks the loader has opened since the last call.
+    fn receive_tracks(&mut self) {
+        loop {
+            // closing a file can block, so the loader does it, and the next
+            // track waits until the one before has gone back
+            if let Some(retired) = self.retired.take() {
+                if let Err(TrySendError::Full(ToLoader::Retired(retired))) = self.link.requests.try_send(ToLoader::Retired(retired)) {
+                    self.retired = Some(retired);
+                    return;
+                }
+            }
+            let Some(mut track) = self.link.tracks.pop() else { return };
+            track.resampler.set_quality(self.quality);
+            self.retired = Some(std::mem::replace(&mut self.file, track));
+            self.waiting = false;
+            self.clear_loop();
+            self.onsets.reset();
+        }
+    }
+
+    fn send(&self, status: Status) {
+        let _ = self.link.status.try_send(FromAudio::Status(status));
+    }
+
+    /// Jumps to `frame` in the song. Returns false if it couldn't.
+    fn seek(&mut self, frame: u64) -> bool {
+        if !self.file.source.seek(frame) {
+            if self.file.source.position().is_some() {
+                self.send(Status::SeekFailed);
+            }
+            return false;
+        }
+        if !self.use_mic {
+            self.file.resampler.reset();
+            self.onsets.reset();
+        }
+        true
+    }
+
+    /// Moves the song `seconds` forwards or backwards.
+    fn seek_by(&mut self, seconds: f32) {
+        if let Some(pos) = self.file.source.position() {
+            let offset = (seconds.abs() * self.file.source.sample_rate() as f32) as u64;
+            let target = if seconds < 0.0 { pos.saturating_sub(offset) } else { pos + offset };
+            self.seek(target);
+        }
+    }
+
+    fn cycle_loop(&mut self) {
+        let Some(pos) = self.file.source.position() else { return };
+        match (self.loop_start, self.loop_end) {
+            (None, _) => {
+                self.loop_start = Some(pos);
+                self.send(Status::LoopStart);
+            }
+            (Some(start), None) if pos > start => {
+                self.loop_end = Some(pos);
+                self.send(Status::Looping);
+            }
+            _ => {
+                self.clear_loop();
+                self.send(Status::LoopCleared);
+            }
+        }
+    }
+
+    fn clear_loop(&mut self) {
+        self.loop_start = None;
+        self.loop_end = None;
+    }
+
+    /// Asks the loader for the track after the one that has run out, once.
+    fn track_finished(&mut self) {
+        if !self.waiting {
+            // tried again on the next frame if there's no room
+            self.waiting = self.link.requests.try_send(ToLoader::Finished).is_ok();
         }
     }
 
     fn handle_commands(&mut self) {
-        while let Some(cmd) = self.cons.pop() {
+        while let Some(cmd) = self.link.commands.pop() {
             match cmd {
                 ToAudio::ToggleVisuals => {
                     self.send_to_gfx = !self.send_to_gfx;
                 },
                 ToAudio::ToggleMic => {
                     self.use_mic = !self.use_mic;
+                    // each starts over from where it left off
+                    self.mic.resampler.reset();
+                    self.file.resampler.reset();
+                    self.onsets.reset();
+                }
+                ToAudio::CycleResampleQuality => {
+                    self.quality = self.quality.next();
+                    self.send(Status::Quality(self.quality));
+                    self.file.resampler.set_quality(self.quality);
+                    self.mic.resampler.set_quality(self.quality);
+                }
+                ToAudio::CycleVisChannel => {
+                    self.vis_channel = self.vis_channel.next(self.frame().len());
+                    self.send(Status::VisChannel(self.vis_channel));
                 }
+                // the loader keeps these
+                ToAudio::NextTrack | ToAudio::PreviousTrack | ToAudio::ToggleShuffle | ToAudio::CycleRepeat => {}
+                ToAudio::TogglePause => {
+                    self.paused = !self.paused;
+                }
+                ToAudio::Seek(seconds) => self.seek_by(seconds),
+                ToAudio::Restart => {
+                    self.seek(0);
+                }
+                ToAudio::CycleLoop => self.cycle_loop(),
             }
         }
     }
 
-    fn next_sample(&mut self) -> f32 {
-        if let Some(sample) = self.wav_src.next() {
-            sample.unwrap_or(0.0)
+    /// The last frame of whatever is playing, in its channel layout.
+    fn frame(&self) -> &[f32] {
+        if self.use_mic {
+            &self.mic.frame
         } else {
-            0.0
+            &self.file.frame
         }
     }
+
+    /// Moves on to the next frame at the output sample rate, leaving it in
+    /// `out_frame`. Returns what the visuals should see of it.
+    fn next_frame(&mut self) -> VisFrame {
+        if !self.use_mic {
+            if self.paused {
+                self.out_frame.fill(0.0);
+                return VisFrame::default();
+            }
+            if let (Some(start), Some(end), Some(pos)) = (self.loop_start, self.loop_end, self.file.source.position()) {
+                // rather than trying again on every frame
+                if pos >= end && !self.seek(start) {
+                    self.clear_loop();
+                }
+            }
+        }
+        if self.use_mic {
+            self.mic.next_frame();
+        } else if !self.file.next_frame() {
+            self.track_finished();
+        }
+        let frame = if self.use_mic { &self.mic.frame } else { &self.file.frame };
+        mix::remix(frame, &mut self.out_frame);
+        VisFrame::new(frame, self.vis_channel)
+    }
 }
 
-pub fn audio_thread() -> (Sender<ToAudio>, Receiver<FromAudio>) {
+pub fn audio_thread(options: Options) -> (Sender<ToAudio>, Receiver<FromAudio>, AudioThread) {
     let (tx_in, rx_in) = crossbeam_channel::bounded(1024);
     let (tx_out, rx_out) = crossbeam_channel::bounded(1024);
-    let _handle = thread::spawn(move || {
-        let host = cpal::default_host();
-        let in_device = host.default_input_device().expect("get input device");
-        let out_device = host.default_output_device().expect("get output device");
-        let cfg_out = out_device.default_output_config().expect("get output config");  
-        let cfg_in = in_device.default_input_config().expect("get output config");  
-        match cfg_out.sample_format() {
-            cpal::SampleFormat::I16 => {
-                audio_handler::<i16>(&in_device, &out_device, &cfg_in.into(), &cfg_out.into(), rx_in.clone(), tx_out.clone()).expect("run i16");
-            },
-            cpal::SampleFormat::U16 => {
-                audio_handler::<u16>(&in_device, &out_device, &cfg_in.into(), &cfg_out.into(), rx_in.clone(), tx_out.clone()).expect("run u16");
-            },
-            cpal::SampleFormat::F32 => {
-                audio_handler::<f32>(&in_device, &out_device, &cfg_in.into(), &cfg_out.into(), rx_in.clone(), tx_out.clone()).expect("run f32");
-            },
+    let (stop_tx, stop_rx) = crossbeam_channel::bounded(1);
+    let handle = thread::spawn(move || {
+        if let Err(e) = run(rx_in, tx_out, stop_rx, &options) {
+            eprintln!("no sound: {:#}", e);
         }
     });
 
-    (tx_in, rx_out)
+    (tx_in, rx_out, AudioThread { stop: stop_tx, handle: Some(handle) })
 }
 
-fn audio_handler<T: Sample>(device_in: &cpal::Device, device_out: &cpal::Device, cfg_in: &cpal::StreamConfig, cfg_out: &cpal::StreamConfig, rx: Receiver<ToAudio>, tx: Sender<FromAudio>) -> anyhow::Result<()> {
+/// Plays audio until something is sent on `stop`, or it or `rx` is hung up.
+fn run(rx: Receiver<ToAudio>, tx: Sender<FromAudio>, stop: Receiver<()>, options: &Options) -> anyhow::Result<()> {
+    let host = cpal::default_host();
+    let in_device = host.default_input_device().context("no input device")?;
+    let out_device = host.default_output_device().context("no output device")?;
+    let cfg_out = out_device.default_output_config().context("get output config")?;
+    let cfg_in = in_device.default_input_config().context("get input config")?;
+    match cfg_out.sample_format() {
+        cpal::SampleFormat::I16 => {
+            audio_handler::<i16>(&in_device, &out_device, &cfg_in.into(), &cfg_out.into(), rx, tx, stop, options)
+        },
+        cpal::SampleFormat::U16 => {
+            audio_handler::<u16>(&in_device, &out_device, &cfg_in.into(), &cfg_out.into(), rx, tx, stop, options)
+        },
+        cpal::SampleFormat::F32 => {
+            audio_handler::<f32>(&in_device, &out_device, &cfg_in.into(), &cfg_out.into(), rx, tx, stop, options)
+        },
+    }
+}
+
+/// Samples of silence that delay a stream of `channels` channels at
+/// `sample_rate` by `latency` milliseconds, in whole frames so the channels
+/// stay in place.
+fn latency_samples(latency: f32, sample_rate: u32, channels: u16) -> usize {
+    let frames = (latency / 1000.0 * sample_rate as f32) as usize;
+    frames * channels as usize
+}
+
+// Nothing in the stream callbacks may block, allocate, panic or touch the
+// disk: they run on the system's realtime audio thread, so whatever doesn't
+// fit is dropped and counted in `Counters` instead, tracks are opened, set
+// up and closed by the `Loader` on this thread, and news goes to the visuals
+// to print.
+#[allow(clippy::too_many_arguments)]
+fn audio_handler<T: Sample>(device_in: &cpal::Device, device_out: &cpal::Device, cfg_in: &cpal::StreamConfig, cfg_out: &cpal::StreamConfig, rx: Receiver<ToAudio>, tx: Sender<FromAudio>, stop: Receiver<()>, options: &Options) -> anyhow::Result<()> {
     let sample_rate = cfg_out.sample_rate.0 as f32;
     let channels = cfg_out.channels as usize;
     let err_fn = |err| {eprintln!("Error on audio stream: {}", err)};
     
-    // get audio data here
-    let audio_rb = HeapRb::new(1024);
-    let (mut audio_prod, audio_cons) = audio_rb.split();
-    let mut audio_data = AudioData::new(audio_cons);
-    
-    let latency_frames = (LATENCY / 1000.0) * sample_rate;
-    let latency_samples = latency_frames as usize * channels;
+    // the ring holds what the input records, so it's measured in its frames
+    let latency_samples = latency_samples(options.latency, cfg_in.sample_rate.0, cfg_in.channels);
 
-    let mic_buf = HeapRb::new(latency_samples * 2);
-    let (mut prod, mut cons) = mic_buf.split();
+    // at least one frame, for latencies shorter than that
+    let mic_buf = HeapRb::new((latency_samples * 2).max(cfg_in.channels as usize));
+    let (mut prod, cons) = mic_buf.split();
 
     for _ in 0..latency_samples {
         // add latency to mic echo
         prod.push(0.0).unwrap()
     }
 
-    // duplicate it to keep track of if mic should do things in both threads
-    let mut dupe_use_mic = audio_data.use_mic;
+    let counters = Arc::new(Counters::default());
+    let playlist = match options.source {
+        SourceKind::File => match Playlist::from_path(&options.music) {
+            Ok(playlist) => Some(playlist),
+            Err(e) => {
+                let _ = tx.try_send(FromAudio::Status(Status::NoSongs(e)));
+                None
+            }
+        },
+        _ => None,
+    };
+    let use_mic = Arc::new(AtomicBool::new(options.use_mic));
+    let (mut loader, link, requests) = loader(playlist, use_mic.clone(), tx.clone(), counters.clone(), options.quality, cfg_out.channels, cfg_out.sample_rate.0);
+    loader.start(options.source);
+    let mic = MicSource::new(cons, cfg_in.channels, cfg_in.sample_rate.0);
+    let mut audio_data = AudioData::new(link, mic, options.use_mic, options.quality, cfg_out.sample_rate.0, cfg_out.channels);
+
+    let (mut vis_tx, vis_rx) = vis_channel((VIS_BUFFER_SECONDS * sample_rate) as usize, counters.clone());
+    if tx.send(FromAudio::Started { sample_rate: cfg_out.sample_rate.0, frames: vis_rx }).is_err() {
+        // the visuals are gone already
+        return Ok(());
+    }
 
+    let in_counters = counters.clone();
     let stream_in = device_in.build_input_stream(
         cfg_in,
         move |data: &[T], _: &_| {
-            let mut need_more_latency = false;
-            while let Ok(cmd) = rx.try_recv() {
-                if let ToAudio::ToggleMic = cmd {
-                    dupe_use_mic = !dupe_use_mic;
-                }
-                audio_prod.push(cmd).expect("audio commands are handled");
-            }
-            for &sample in data {
-                if dupe_use_mic {
-                    if prod.push(sample.to_f32()).is_err() {
-                        need_more_latency = true;
-                    }
+            if use_mic.load(Ordering::Relaxed) {
+                // drop what doesn't fit, the output is falling behind
+                let dropped = data.iter().filter(|sample| prod.push(sample.to_f32()).is_err()).count();
+                if dropped > 0 {
+                    in_counters.mic_overflows.fetch_add(dropped as u64, Ordering::Relaxed);
                 }
             }
-            // if need_more_latency {
-            //     eprintln!("output is falling behind, need more latency!");
-            // }
         },
         err_fn,
     )?;
@@ -141,37 +705,197 @@ fn audio_handler<T: Sample>(device_in: &cpal::Device, device_out: &cpal::Device,
     let stream_out = device_out.build_output_stream(
         cfg_out,
         move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
-            let mut need_more_latency = false;
+            audio_data.receive_tracks();
             audio_data.handle_commands();
             for frame in data.chunks_mut(channels) {
-                for sample in frame.iter_mut() {
-                    let tmp = if audio_data.use_mic {
-                        match cons.pop() {
-                            Some(s) => s,
-                            None => {
-                                need_more_latency = true;
-                                0.0
-                            }
-                        }
-                    } else {
-                        audio_data.next_sample()
-                    };
-                    if audio_data.send_to_gfx { // send it to the graphics part
-                        tx.send(FromAudio::Data(tmp)).expect("send response to gfx thread");
+                let vis = audio_data.next_frame();
+                if audio_data.send_to_gfx { // send it to the graphics part
+                    vis_tx.send(vis);
+                    if let Some(onset) = audio_data.onsets.push(vis.mono) {
+                        // rare enough that there's always room, unless the
+                        // visuals are stuck, and then they won't be missed
+                        let _ = tx.try_send(FromAudio::Onset(onset));
                     }
-                    *sample = cpal::Sample::from(&tmp);
+                }
+                for (sample, value) in frame.iter_mut().zip(&audio_data.out_frame) {
+                    *sample = cpal::Sample::from(value);
                 }
             }
-            // if need_more_latency {
-            //     eprintln!("input is falling behind, need more latency!");
-            // }
+            counters.mic_underflows.store(audio_data.mic.source.underflows(), Ordering::Relaxed);
         },
         err_fn
     )?;
 
     stream_out.play()?;
     stream_in.play()?;
-    std::thread::park(); // leaves the thread to run until app ends
+    // runs until the app ends, then the streams are dropped and stop
+    loader.run(rx, requests, stop);
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_is_whole_input_frames() {
+        assert_eq!(latency_samples(150.0, 48000, 1), 7200);
+        assert_eq!(latency_samples(150.0, 44100, 2), 13230);
+        // 441.4 frames, rounded down
+        assert_eq!(latency_samples(10.01, 44100, 2), 882);
+        assert_eq!(latency_samples(0.01, 44100, 2), 0);
+    }
+
+    #[test]
+    fn vis_channel_drops_what_does_not_fit() {
+        let (mut tx, mut rx) = vis_channel(4, Arc::default());
+        for i in 0..6 {
+            tx.send(VisFrame { mono: i as f32, ..VisFrame::default() });
+        }
+        assert_eq!(rx.dropped(), 2);
+        let received: Vec<f32> = std::iter::from_fn(|| rx.recv()).map(|f| f.mono).collect();
+        assert_eq!(received, [0.0, 1.0, 2.0, 3.0]);
+        tx.send(VisFrame::default());
+        assert!(rx.recv().is_some());
+        assert_eq!(rx.dropped(), 2);
+    }
+
+    /// A fresh temporary directory with a mono wav file of `frames` frames
+    /// for each of `tracks`, or a broken one where that is 0.
+    fn playlist_dir(name: &str, tracks: &[(&str, usize)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("visgra_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        for &(track, frames) in tracks {
+            let path = dir.join(track);
+            if frames == 0 {
+                std::fs::write(&path, b"not a wav file").unwrap();
+                continue;
+            }
+            let spec = hound::WavSpec { channels: 1, sample_rate: 8000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+            let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+            for _ in 0..frames {
+                writer.write_sample(1000i16).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        dir
+    }
+
+    /// A loader for the tracks in `dir`, and the output side at 8 kHz mono
+    /// connected to it.
+    fn connect(dir: &Path) -> (Loader, Receiver<ToLoader>, AudioData, Receiver<FromAudio>) {
+        let (status_tx, status_rx) = crossbeam_channel::bounded(64);
+        let playlist = Playlist::from_path(dir).ok();
+        let (loader, link, requests) = loader(playlist, Arc::default(), status_tx, Arc::default(), Quality::default(), 1, 8000);
+        let (_, cons) = HeapRb::new(1).split();
+        let data = AudioData::new(link, MicSource::new(cons, 1, 8000), false, Quality::default(), 8000, 1);
+        (loader, requests, data, status_rx)
+    }
+
+    fn statuses(rx: &Receiver<FromAudio>) -> Vec<String> {
+        rx.try_iter()
+            .filter_map(|message| match message {
+                FromAudio::Status(status) => Some(status.to_string()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Hands everything the output side asked for to `loader`, returning
+    /// how many tracks had finished.
+    fn serve(loader: &mut Loader, requests: &Receiver<ToLoader>) -> usize {
+        let mut finished = 0;
+        for request in requests.try_iter() {
+            finished += matches!(request, ToLoader::Finished) as usize;
+            loader.request(request);
+        }
+        finished
+    }
+
+    #[test]
+    fn loader_replaces_finished_tracks_and_skips_broken_ones() {
+        let dir = playlist_dir("loader", &[("a.wav", 100), ("b.wav", 0), ("c.wav", 200)]);
+        let (mut loader, requests, mut data, status) = connect(&dir);
+        loader.start(SourceKind::File);
+        assert_eq!(statuses(&status), [format!("playing {}", dir.join("a.wav").display())]);
+        data.receive_tracks();
+        assert_eq!(data.file.source.position(), Some(0));
+
+        for _ in 0..150 {
+            data.next_frame();
+        }
+        // asked for the next track once, and played silence while waiting
+        assert_eq!(serve(&mut loader, &requests), 1);
+        assert_eq!(data.next_frame().mono, 0.0);
+        let status = statuses(&status);
+        assert_eq!(status.len(), 2, "{:?}", status);
+        assert!(status[0].starts_with(&format!("skipping {}", dir.join("b.wav").display())), "{}", status[0]);
+        assert_eq!(status[1], format!("playing {}", dir.join("c.wav").display()));
+
+        data.receive_tracks();
+        assert_eq!(data.file.source.position(), Some(0));
+        assert!(data.next_frame().mono > 0.0);
+        // the finished track went back to be closed
+        assert!(matches!(requests.try_recv(), Ok(ToLoader::Retired(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loader_keeps_the_playlist_and_passes_the_rest_on() {
+        let dir = playlist_dir("commands", &[("a.wav", 50)]);
+        let (mut loader, requests, mut data, status) = connect(&dir);
+        loader.start(SourceKind::File);
+        loader.command(ToAudio::CycleRepeat);
+        loader.command(ToAudio::ToggleMic);
+        assert!(loader.use_mic.load(Ordering::Relaxed));
+        data.receive_tracks();
+        data.handle_commands();
+        assert!(data.use_mic);
+        assert_eq!(statuses(&status)[1..], ["repeat Off"]);
+
+        loader.command(ToAudio::ToggleMic);
+        data.handle_commands();
+        for _ in 0..100 {
+            data.next_frame();
+        }
+        serve(&mut loader, &requests);
+        assert_eq!(statuses(&status), ["end of playlist"]);
+        data.receive_tracks();
+        assert_eq!(data.file.source.position(), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_tracks_wait_for_room_to_go_back() {
+        let dir = playlist_dir("retired", &[("a.wav", 50)]);
+        let (mut loader, requests, mut data, _status) = connect(&dir);
+        for _ in 0..TRACK_QUEUE * 2 + 2 {
+            loader.start(SourceKind::File);
+            data.receive_tracks();
+        }
+        // the queue back is full, so the last track replaced is kept and
+        // the newest one isn't played yet
+        assert_eq!(requests.len(), TRACK_QUEUE * 2);
+        assert!(data.retired.is_some());
+        assert_eq!(data.link.tracks.len(), 1);
+
+        serve(&mut loader, &requests);
+        data.receive_tracks();
+        // both the kept track and the one the newest replaced went back
+        assert_eq!(requests.len(), 2);
+        assert!(data.retired.is_none());
+        assert!(data.link.tracks.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn loader_plays_a_synthetic_source_instead_of_songs() {
+        let (mut loader, _, mut data, status) = connect(Path::new("no such directory"));
+        loader.start(SourceKind::Tone);
+        data.receive_tracks();
+        assert!((0..100).any(|_| data.next_frame().mono != 0.0));
+        assert!(statuses(&status).is_empty());
+    }
+}
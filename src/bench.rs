@@ -0,0 +1,71 @@
+use crate::{
+    audio,
+    cli::Args,
+    map::Map,
+    mix::{VisChannel, VisFrame},
+    source::{Source, Tone},
+    texture::Texture,
+    Game,
+};
+use ringbuf::Rb;
+use std::time::{Duration, Instant};
+
+const SIZES: [(usize, usize); 2] = [(1280, 720), (3840, 2160)];
+const FRAMES: u32 = 10;
+
+/// Prints the average time it takes to render a frame with the software
+/// renderer, on one and on all threads, and with the line mesh it replaced,
+/// at a few screen sizes.
+/// Neither includes uploading the result to the GPU.
+pub fn run(args: &Args, map: Map) {
+    let (tx, _) = crossbeam_channel::bounded::<audio::ToAudio>(1);
+    let (_, rx) = crossbeam_channel::bounded::<audio::FromAudio>(1);
+    let threads = args.render_threads();
+    let mut game = Game::new(map, args.render_distance, args.fov.to_radians(), args.wall_settings(), threads, tx, rx);
+    let mut tone = Tone::new(440.0, 0.5, 44100);
+    game.set_sample_rate(44100);
+    for _ in 0..game.wave_buffer.capacity() {
+        let sample = tone.next_sample().unwrap_or(0.0);
+        game.push_frame(VisFrame::new(&[sample], VisChannel::Mix));
+    }
+    game.update_wall_textures();
+
+    for (width, height) in SIZES {
+        let mesh = average(|| {
+            // the floor and ceiling went through a framebuffer even then
+            let mut background = Texture::new(width, height);
+            game.render_floor_ceiling(&mut background.rows_mut(), width, height);
+            let _ = background.to_rgba8();
+            if let Ok(mb) = game.wall_mesh(width as f32, height as f32) {
+                let _ = mb.build();
+            }
+        });
+        game.set_threads(1);
+        let framebuffer = average(|| render(&game, width, height));
+        game.set_threads(threads);
+        let threaded = average(|| render(&game, width, height));
+        println!(
+            "{}x{}: line mesh {:.1} ms, framebuffer {:.1} ms, on {} threads {:.1} ms",
+            width,
+            height,
+            mesh.as_secs_f64() * 1000.0,
+            framebuffer.as_secs_f64() * 1000.0,
+            threads,
+            threaded.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+fn render(game: &Game, width: usize, height: usize) {
+    let mut frame = Texture::new(width, height);
+    game.render_frame(&mut frame);
+    let _ = frame.to_rgba8();
+}
+
+fn average(mut f: impl FnMut()) -> Duration {
+    let start = Instant::now();
+    for _ in 0..FRAMES {
+        f();
+    }
+    start.elapsed() / FRAMES
+}
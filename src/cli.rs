@@ -0,0 +1,293 @@
+use crate::{
+    colormap::ColorMap,
+    BeatEffect, Shading,
+    map::Map,
+    resample::Quality,
+    source::SourceKind,
+    walls::{self, WaveMode},
+};
+use clap::{error::ErrorKind, CommandFactory, Parser, ValueEnum};
+use ggez::graphics::Color;
+use std::{num::NonZeroUsize, path::PathBuf, thread};
+
+/// Widest and highest the waveform textures can be, so a typo in the
+/// options doesn't allocate gigabytes.
+const MAX_TEXTURE_WIDTH: i64 = 1 << 14;
+const MAX_TEXTURE_HEIGHT: i64 = 1 << 10;
+
+/// A visualization of sound as a texture in a pseudo-3D environment.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Args {
+    /// Wav file to play, or a directory of wav files
+    #[arg(default_value = "./music")]
+    pub music: PathBuf,
+
+    /// Map file to play in, see ./maps for examples
+    #[arg(long)]
+    pub map: Option<PathBuf>,
+
+    /// What the `#` walls show at the start
+    #[arg(long, value_enum, default_value_t = WaveMode::default())]
+    pub walls: WaveMode,
+
+    /// What happens on each beat
+    #[arg(long, value_enum, default_value_t = BeatEffect::default())]
+    pub beat: BeatEffect,
+
+    /// What else besides distance makes the walls darker
+    #[arg(long, value_enum, default_value_t = Shading::default())]
+    pub shading: Shading,
+
+    /// Colors of the spectrum heat map and spectrogram
+    #[arg(long, value_enum, default_value_t = ColorMap::default())]
+    pub colormap: ColorMap,
+
+    /// Level shown as the bottom of the spectrum views, in dB
+    #[arg(long, default_value_t = -72.0, allow_negative_numbers = true)]
+    pub min_db: f32,
+
+    /// Level shown as the top of the spectrum views, in dB
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub max_db: f32,
+
+    /// Thickness of the waveform line, in texels
+    #[arg(long, default_value_t = 1.5, value_parser = between(0.0, MAX_TEXTURE_HEIGHT as f32))]
+    pub line_width: f32,
+
+    /// Draw the waveform with hard edges
+    #[arg(long)]
+    pub no_antialias: bool,
+
+    /// Fill the waveform in down to its middle
+    #[arg(long)]
+    pub fill: bool,
+
+    /// Vertical scale of the waveform, 1 makes full scale reach the edges
+    #[arg(long, default_value_t = 1.0, value_parser = between(0.0, 1000.0))]
+    pub wave_scale: f32,
+
+    /// How much audio the waveform walls show, in milliseconds
+    #[arg(long, default_value_t = 100.0, value_parser = between(0.0, 10_000.0))]
+    pub window_ms: f32,
+
+    /// Width of the waveform textures, in texels
+    #[arg(long, default_value_t = 4410, value_parser = clap::value_parser!(u32).range(1..=MAX_TEXTURE_WIDTH))]
+    pub texture_width: u32,
+
+    /// Height of the waveform textures, in texels
+    #[arg(long, default_value_t = 32, value_parser = clap::value_parser!(u32).range(2..=MAX_TEXTURE_HEIGHT))]
+    pub texture_height: u32,
+
+    /// Color of the waveform, as hex RGB
+    #[arg(long, value_parser = parse_color, default_value = "000000")]
+    pub foreground: Color,
+
+    /// Color behind the waveform, as hex RGB
+    #[arg(long, value_parser = parse_color, default_value = "ffffff")]
+    pub background: Color,
+
+    /// Window width in pixels
+    #[arg(long, default_value_t = 1280, value_parser = clap::value_parser!(u32).range(1..))]
+    pub width: u32,
+
+    /// Window height in pixels
+    #[arg(long, default_value_t = 720, value_parser = clap::value_parser!(u32).range(1..))]
+    pub height: u32,
+
+    /// Start in fullscreen
+    #[arg(long)]
+    pub fullscreen: bool,
+
+    /// How far away walls are still drawn, in tiles
+    #[arg(long, default_value_t = 20.0, value_parser = between(0.0, f32::INFINITY))]
+    pub render_distance: f32,
+
+    /// Horizontal field of view in degrees
+    #[arg(long, default_value_t = 90.0, value_parser = between(0.0, 180.0))]
+    pub fov: f32,
+
+    /// Delay between microphone input and output, in milliseconds
+    #[arg(long, default_value_t = 150.0, value_parser = between(0.0, 10_000.0))]
+    pub latency: f32,
+
+    /// Whether to start out playing music or the microphone
+    #[arg(long, value_enum, default_value_t = Mode::File)]
+    pub mode: Mode,
+
+    /// What plays instead of the microphone, the wav files or a test signal
+    #[arg(long, value_enum, default_value_t = SourceKind::default())]
+    pub source: SourceKind,
+
+    /// How songs are resampled to the output device's sample rate
+    #[arg(long, value_enum, default_value_t = Quality::default())]
+    pub resample: Quality,
+
+    /// Render the song to PNG frames in this directory instead of opening a window
+    #[arg(long, value_name = "DIR")]
+    pub render: Option<PathBuf>,
+
+    /// Frame rate of the rendered frames
+    #[arg(long, default_value_t = 30, requires = "render", value_parser = clap::value_parser!(u32).range(1..))]
+    pub fps: u32,
+
+    /// Threads to render with [default: one per CPU core]
+    #[arg(long)]
+    pub threads: Option<NonZeroUsize>,
+
+    /// Measure how long it takes to render a frame, then exit
+    #[arg(long, conflicts_with = "render")]
+    pub bench: bool,
+}
+
+impl Args {
+    /// Parses the command line, exiting with a usage error if options that
+    /// are fine on their own don't go together.
+    pub fn parse_valid() -> Self {
+        let args = Self::parse();
+        if let Err(message) = args.validate() {
+            Self::command().error(ErrorKind::ArgumentConflict, message).exit();
+        }
+        args
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.render.is_some() && self.source != SourceKind::File {
+            return Err(format!("--render needs wav files to render, not --source {:?}", self.source));
+        }
+        if !self.min_db.is_finite() || !self.max_db.is_finite() {
+            return Err(format!("--min-db ({}) and --max-db ({}) have to be numbers", self.min_db, self.max_db));
+        }
+        if self.min_db >= self.max_db {
+            return Err(format!("--min-db ({}) has to be below --max-db ({})", self.min_db, self.max_db));
+        }
+        Ok(())
+    }
+
+    pub fn wall_settings(&self) -> walls::Settings {
+        walls::Settings {
+            mode: self.walls,
+            colormap: self.colormap,
+            min_db: self.min_db,
+            max_db: self.max_db,
+            wave: walls::WaveStyle {
+                thickness: self.line_width,
+                antialias: !self.no_antialias,
+                fill: self.fill,
+                scale: self.wave_scale,
+                foreground: self.foreground,
+                background: self.background,
+            },
+            texture_width: self.texture_width as usize,
+            texture_height: self.texture_height as usize,
+            window_ms: self.window_ms,
+        }
+    }
+
+    /// Number of threads given with `--threads`, or one per core.
+    pub fn render_threads(&self) -> usize {
+        self.threads
+            .or_else(|| thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+
+    /// The map given with `--map`, or the default one.
+    pub fn load_map(&self) -> anyhow::Result<Map> {
+        match &self.map {
+            Some(path) => Map::load(path),
+            None => Map::parse(crate::map::DEFAULT),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    File,
+    Mic,
+}
+
+/// Parser for numbers strictly between `min` and `max`.
+fn between(min: f32, max: f32) -> impl Fn(&str) -> Result<f32, String> + Clone + Send + Sync + 'static {
+    move |s| {
+        let value: f32 = s.parse().map_err(|_| format!("`{}` is not a number", s))?;
+        match (value > min, value < max) {
+            (true, true) => Ok(value),
+            _ if max == f32::INFINITY => Err(format!("must be more than {}", min)),
+            _ => Err(format!("must be more than {} and less than {}", min, max)),
+        }
+    }
+}
+
+/// Parses colors like `ff8800` or `#ff8800`.
+fn parse_color(s: &str) -> Result<Color, String> {
+    let hex = s.strip_prefix('#').unwrap_or(s);
+    match u32::from_str_radix(hex, 16) {
+        Ok(rgb) if hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(Color::from_rgb_u32(rgb)),
+        _ => Err(format!("`{}` is not a hex RGB color like ff8800", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("visgra_project").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn only_files_can_be_rendered() {
+        assert!(parse(&["--source", "sweep"]).unwrap().validate().is_ok());
+        assert!(parse(&["--render", "out"]).unwrap().validate().is_ok());
+        assert!(parse(&["--render", "out", "--source", "tone"]).unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        let args = parse(&[]).unwrap();
+        assert_eq!((args.width, args.height), (1280, 720));
+    }
+
+    #[test]
+    fn numbers_out_of_range_are_rejected() {
+        for args in [
+            ["--latency", "0"],
+            ["--latency", "0.0"],
+            ["--fov", "180"],
+            ["--fov", "nan"],
+            ["--render-distance", "0"],
+            ["--render-distance", "inf"],
+            ["--width", "0"],
+            ["--height", "0"],
+            ["--window-ms", "0"],
+            ["--window-ms", "1e9"],
+            ["--texture-width", "0"],
+            ["--texture-width", "16385"],
+            ["--texture-height", "1"],
+            ["--texture-height", "1025"],
+            ["--line-width", "0"],
+            ["--line-width", "nan"],
+            ["--line-width", "inf"],
+            ["--wave-scale", "-1"],
+            ["--wave-scale", "nan"],
+            ["--wave-scale", "1e9"],
+        ] {
+            assert!(parse(&args).is_err(), "{:?}", args);
+        }
+        assert!(parse(&["--latency", "0.01", "--fov", "179", "--window-ms", "1"]).is_ok());
+        assert!(parse(&["--texture-width", "16384", "--texture-height", "1024"]).is_ok());
+        assert!(parse(&["--line-width", "0.5", "--wave-scale", "20"]).is_ok());
+    }
+
+    #[test]
+    fn db_range_has_to_be_ascending() {
+        let range = |min: &str, max: &str| parse(&["--min-db", min, "--max-db", max]).unwrap().validate();
+        assert!(range("-60", "0").is_ok());
+        assert!(range("-6", "-6").is_err());
+        assert!(range("0", "-60").is_err());
+        assert!(range("nan", "0").is_err());
+        assert!(range("-60", "nan").is_err());
+        assert!(range("-inf", "0").is_err());
+        assert!(range("-60", "inf").is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use ggez::graphics::Color;
+
+/// Ways of turning a level in 0..1 into a color.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ColorMap {
+    #[default]
+    Viridis,
+    Magma,
+    Grayscale,
+}
+
+/// Evenly spaced samples of matplotlib's color maps of the same name.
+const VIRIDIS: [u32; 9] = [
+    0x440154, 0x472d7b, 0x3b528b, 0x2c728e, 0x21918c, 0x28ae80, 0x5ec962, 0xaddc30, 0xfde725,
+];
+const MAGMA: [u32; 9] = [
+    0x000004, 0x1c1044, 0x4f127b, 0x812581, 0xb5367a, 0xe55064, 0xfb8761, 0xfec287, 0xfcfdbf,
+];
+const GRAYSCALE: [u32; 2] = [0x000000, 0xffffff];
+
+impl ColorMap {
+    pub fn next(self) -> Self {
+        match self {
+            ColorMap::Viridis => ColorMap::Magma,
+            ColorMap::Magma => ColorMap::Grayscale,
+            ColorMap::Grayscale => ColorMap::Viridis,
+        }
+    }
+
+    pub fn color(self, t: f32) -> Color {
+        let stops: &[u32] = match self {
+            ColorMap::Viridis => &VIRIDIS,
+            ColorMap::Magma => &MAGMA,
+            ColorMap::Grayscale => &GRAYSCALE,
+        };
+        let pos = t.clamp(0.0, 1.0) * (stops.len() - 1) as f32;
+        let i = (pos as usize).min(stops.len() - 2);
+        let (a, b) = (Color::from_rgb_u32(stops[i]), Color::from_rgb_u32(stops[i + 1]));
+        let f = pos - i as f32;
+        Color::new(a.r + (b.r - a.r) * f, a.g + (b.g - a.g) * f, a.b + (b.b - a.b) * f, 1.0)
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{
+    audio,
+    cli::Args,
+    map::Map,
+    mix::{VisChannel, VisFrame},
+    onset::OnsetDetector,
+    playlist::Playlist,
+    source::{Source, WavSource},
+    texture::Texture,
+    Game,
+};
+use anyhow::Context;
+use std::path::Path;
+
+/// Renders the song picked by `args`, as seen from the spawn point of `map`,
+/// to a numbered PNG sequence in `out_dir`
+/// without opening a window or an audio device. The song is consumed at a
+/// fixed rate of `args.fps` frames per second, so the same input always
+/// gives the same frames.
+pub fn render(args: &Args, map: Map, out_dir: &Path) -> anyhow::Result<()> {
+    let fps = args.fps;
+    anyhow::ensure!(fps > 0, "fps must be positive");
+    let playlist = Playlist::from_path(&args.music)?;
+    let wav = playlist.current();
+    let mut source = WavSource::open(wav).with_context(|| format!("open {}", wav.display()))?;
+    let sample_rate = source.sample_rate();
+    anyhow::ensure!(
+        fps <= sample_rate,
+        "fps can't be higher than the song's sample rate, {} Hz",
+        sample_rate
+    );
+    let mut frame = vec![0.0; source.channels() as usize];
+
+    std::fs::create_dir_all(out_dir)
+        .with_context(|| format!("create {}", out_dir.display()))?;
+
+    // nothing is ever sent to or received from an audio thread here
+    let (tx, _) = crossbeam_channel::bounded::<audio::ToAudio>(1);
+    let (_, rx) = crossbeam_channel::bounded::<audio::FromAudio>(1);
+    let mut game = Game::new(map, args.render_distance, args.fov.to_radians(), args.wall_settings(), args.render_threads(), tx, rx);
+    game.beat_effect = args.beat;
+    game.shading = args.shading;
+    game.set_sample_rate(source.sample_rate());
+    let mut onsets = OnsetDetector::new(source.sample_rate());
+    let mut canvas = Texture::new(args.width as usize, args.height as usize);
+
+    let mut index = 0;
+    let mut position = 0;
+    loop {
+        let end = image_end(index, sample_rate, fps);
+        let mut read = 0;
+        while position < end && read_frame(&mut source, &mut frame) {
+            let vis = VisFrame::new(&frame, VisChannel::Mix);
+            game.push_frame(vis);
+            if let Some(onset) = onsets.push(vis.mono) {
+                game.on_onset(onset);
+            }
+            position += 1;
+            read += 1;
+        }
+        if read == 0 {
+            break;
+        }
+        game.fade_beat(1.0 / fps as f32);
+        game.update_wall_textures();
+        game.render_frame(&mut canvas);
+
+        let path = out_dir.join(format!("frame_{:06}.png", index));
+        image::RgbaImage::from_raw(args.width, args.height, canvas.to_rgba8())
+            .expect("frame buffer matches frame size")
+            .save(&path)
+            .with_context(|| format!("write {}", path.display()))?;
+        index += 1;
+    }
+    eprintln!("rendered {} frames to {}", index, out_dir.display());
+    Ok(())
+}
+
+/// Frame of the song that image `index` ends before. Images get a whole
+/// number of frames each, but they add up to exactly `fps` images per second
+/// instead of drifting when `fps` doesn't divide `sample_rate`.
+fn image_end(index: u64, sample_rate: u32, fps: u32) -> u64 {
+    (index + 1) * sample_rate as u64 / fps as u64
+}
+
+/// Reads one interleaved frame from `source`, returning false at the end.
+fn read_frame(source: &mut dyn Source, frame: &mut [f32]) -> bool {
+    for sample in frame.iter_mut() {
+        match source.next_sample() {
+            Some(s) => *sample = s,
+            None => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn images_keep_pace_with_the_song() {
+        // 44100 / 60 = 735, 44100 / 24 = 1837.5, 48000 / 44100 isn't whole
+        for (sample_rate, fps) in [(44100, 60), (44100, 24), (48000, 44100), (44100, 44100)] {
+            let mut start = 0;
+            for index in 0..fps as u64 {
+                let end = image_end(index, sample_rate, fps);
+                let length = end - start;
+                assert!(length == (sample_rate / fps) as u64 || length == (sample_rate / fps) as u64 + 1);
+                start = end;
+            }
+            assert_eq!(start, sample_rate as u64, "{} fps at {} Hz", fps, sample_rate);
+        }
+    }
+}
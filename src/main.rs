@@ -1,37 +1,81 @@
 use ggez::{
-    conf::{WindowMode, WindowSetup},
+    conf::{FullscreenType, WindowMode, WindowSetup},
     event::{self, EventHandler},
     glam::{ivec2, vec2, IVec2, Vec2},
-    graphics::{self, Color, DrawParam, Drawable, Mesh, Text},
+    graphics::{self, Color, DrawParam, Drawable, Text},
     input::keyboard::{KeyCode, KeyInput},
-    timer, Context, ContextBuilder, GameError, GameResult,
+    timer, Context, ContextBuilder, GameResult,
 };
-use ringbuf::{LocalRb, Rb};
-use std::{f32::consts::PI, str::from_utf8, mem::MaybeUninit};
+use ringbuf::{HeapRb, Rb};
+use rayon::iter::{IntoParallelIterator, ParallelBridge, ParallelIterator};
+use std::str::from_utf8;
 use crossbeam_channel::{Sender, Receiver};
 mod audio;
+mod bench;
+mod cli;
+mod colormap;
+mod headless;
+mod map;
+mod meter;
+mod mix;
+mod onset;
+mod playlist;
+mod resample;
+mod source;
+mod spectrum;
+mod tempo;
 mod texture;
+mod walls;
 
-const WAVE_SIZE: usize = 4410;
-const WAVE_HEIGHT: usize = 32;
-const WAVE_MUL: f32 = (WAVE_HEIGHT - 1) as f32 / 2.0;
-
-const MAP: &str = "#########.......\
-#...............\
-#.......########\
-#..............#\
-#......##......#\
-#......##......#\
-#..............#\
-###............#\
-##.............#\
-#......####..###\
-#......#.......#\
-#......#.......#\
-#..............#\
-#......#########\
-#..............#\
-################";
+/// Sample rate assumed until the audio thread tells the real one.
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+const SEEK_SECONDS: f32 = 5.0;
+/// Seconds it takes a beat to fade to a third of its strength.
+const BEAT_TIME: f32 = 0.15;
+/// How much brighter the walls get on the strongest beats.
+const BEAT_BRIGHTNESS: f32 = 0.6;
+/// How far the view turns on the strongest beats, in radians.
+const BEAT_SHAKE: f32 = 0.04;
+/// Momentary loudness in LUFS at which loudness shading is darkest.
+const QUIET_LUFS: f32 = -40.0;
+/// Momentary loudness in LUFS at which loudness shading is brightest.
+const LOUD_LUFS: f32 = -10.0;
+/// How bright the walls are with loudness shading when it's quiet.
+const QUIET_BRIGHTNESS: f32 = 0.25;
+
+/// What happens on each beat.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum BeatEffect {
+    None,
+    /// The walls get brighter.
+    #[default]
+    Pulse,
+    /// The ceiling lights up.
+    Flash,
+    /// The view shakes.
+    Shake,
+}
+
+/// What the walls' brightness depends on, besides their distance.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Shading {
+    /// Only the distance.
+    #[default]
+    Distance,
+    /// How loud the music is right now.
+    Loudness,
+}
+
+impl BeatEffect {
+    fn next(self) -> Self {
+        match self {
+            BeatEffect::None => BeatEffect::Pulse,
+            BeatEffect::Pulse => BeatEffect::Flash,
+            BeatEffect::Flash => BeatEffect::Shake,
+            BeatEffect::Shake => BeatEffect::None,
+        }
+    }
+}
 
 enum Direction {
     Forward,
@@ -63,11 +107,11 @@ struct Player {
 }
 
 impl Player {
-    fn new(x: f32, y: f32) -> Self {
+    fn new(x: f32, y: f32, fov: f32) -> Self {
         Self {
             pos: vec2(x, y),
             angle: 0.,
-            fov: PI / 2., // 90 degrees
+            fov,
             speed: 2.,
             controller: InputState::new(),
         }
@@ -122,6 +166,30 @@ impl Player {
     }
 }
 
+/// Result of casting a single ray through the map.
+struct Column {
+    /// Kind of wall that was hit, if any.
+    wall: Option<map::WallKind>,
+    /// Distance to the wall along the direction the player is facing, so
+    /// walls don't bulge towards the middle of the screen.
+    distance: f32,
+    /// Where on the wall's face the ray hit, from 0 on its left edge to 1
+    /// on its right.
+    texture_x: f32,
+    /// How much of the wall's width the column covers.
+    texture_width: f32,
+    shade: f32,
+}
+
+impl Column {
+    /// Screen rows where the wall hit by this column starts and ends.
+    fn wall_span(&self, screen_height: f32) -> (f32, f32) {
+        let ceil_distance = (screen_height / 2.) - screen_height / self.distance;
+        let floor_distance = screen_height - ceil_distance;
+        (ceil_distance, floor_distance)
+    }
+}
+
 struct Game {
     size: IVec2,
     //_map: Vec<char>,
@@ -131,119 +199,360 @@ struct Game {
     draw_map: bool,
     tx: Sender<audio::ToAudio>,
     rx: Receiver<audio::FromAudio>,
-    wall_texture: texture::Texture,
-    wave_buffer: LocalRb<f32, Vec<MaybeUninit<f32>>>,
+    /// Frames from the audio thread, once it has started.
+    audio_frames: Option<audio::VisReceiver>,
+    /// Stops the audio thread when the game quits.
+    audio: Option<audio::AudioThread>,
+    walls: walls::Walls,
+    wave_buffer: walls::WaveBuffer,
+    /// What is shown on screen, rendered in software.
+    frame: texture::Texture,
+    /// Threads the rays and the rows of the screen are split between,
+    /// kept from frame to frame.
+    pool: rayon::ThreadPool,
+    beat_effect: BeatEffect,
+    /// Strength of the last beat, fading towards 0.
+    beat: f32,
+    /// How far the view is turned by the shaking, in radians.
+    shake: f32,
+    /// Seconds the beat has been fading for in total, which drives the
+    /// shaking.
+    clock: f32,
+    tempo: tempo::TempoTracker,
+    meter: meter::Meter,
+    shading: Shading,
+    sample_rate: u32,
+    /// Frames of audio pushed since the sample rate was set, which is how
+    /// far into the music the onsets' times are counted.
+    frames: u64,
 }
 
 impl Game {
-    fn new(width: i32, height: i32, render_distance: f32, tx: Sender<audio::ToAudio>, rx: Receiver<audio::FromAudio>) -> Self {
+    fn new(map: map::Map, render_distance: f32, fov: f32, wall_settings: walls::Settings, threads: usize, tx: Sender<audio::ToAudio>, rx: Receiver<audio::FromAudio>) -> Self {
+        let mut player = Player::new(map.spawn.x, map.spawn.y, fov);
+        player.angle = map.direction;
+        let mut walls = walls::Walls::new(wall_settings);
+        walls.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        let wave_buffer = HeapRb::new(walls.history());
         Self {
-            size: ivec2(width, height),
+            size: ivec2(map.width, map.height),
             render_distance,
-            player: Player::new(14., 5.),
-            //map: MAP.chars().collect(),
-            map: MAP.as_bytes().iter().map(|x| *x).collect(),
+            player,
+            map: map.tiles,
             draw_map: false,
             tx,
             rx,
-            wall_texture: texture::Texture::new(WAVE_SIZE, WAVE_HEIGHT),
-            wave_buffer: LocalRb::new(WAVE_SIZE)
+            audio_frames: None,
+            audio: None,
+            walls,
+            wave_buffer,
+            frame: texture::Texture::default(),
+            pool: render_pool(threads),
+            beat_effect: BeatEffect::default(),
+            beat: 0.0,
+            shake: 0.0,
+            clock: 0.0,
+            tempo: tempo::TempoTracker::new(),
+            meter: meter::Meter::new(DEFAULT_SAMPLE_RATE),
+            shading: Shading::default(),
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            frames: 0,
         }
     }
 
-    fn raycast(&self, ctx: &Context) -> GameResult<Mesh> {
-        let (screen_width, screen_height) = ctx.gfx.drawable_size();
-        let mut mb = graphics::MeshBuilder::new();
-        for x in 0..screen_width as u32 {
-            // raycasting
-            let ray_angle = self.player.angle - self.player.fov / 2.
-                + (x as f32 / screen_width) * self.player.fov;
-            let ray_direction = vec2(ray_angle.cos(), ray_angle.sin());
-            let step_size = vec2(
-                (1. + (ray_direction.y / ray_direction.x) * (ray_direction.y / ray_direction.x))
-                    .sqrt(),
-                (1. + (ray_direction.x / ray_direction.y) * (ray_direction.x / ray_direction.y))
-                    .sqrt(),
-            );
-            let mut map_check = self.player.pos.as_ivec2();
-            let step = ivec2(
-                ray_direction.x.signum() as i32,
-                ray_direction.y.signum() as i32,
-            );
-            let mut ray_length1d = vec2(
-                if ray_direction.x < 0.0 {
-                    (self.player.pos.x - map_check.x as f32) * step_size.x
-                } else {
-                    ((map_check.x + 1) as f32 - self.player.pos.x) * step_size.x
-                },
-                if ray_direction.y < 0.0 {
-                    (self.player.pos.y - map_check.y as f32) * step_size.y
-                } else {
-                    ((map_check.y + 1) as f32 - self.player.pos.y) * step_size.y
-                },
-            );
+    /// Direction the player is looking in.
+    fn facing(&self) -> Vec2 {
+        let angle = self.player.angle + self.shake;
+        vec2(angle.cos(), angle.sin())
+    }
 
-            let mut tile_found = false;
-            let mut distance = 0.0;
-            let mut texture_sample_x = -1.; // set to smth valid when hit wall
-            
-            while !tile_found && distance < self.render_distance {
-                // walk shortest path
-                if ray_length1d.x < ray_length1d.y {
-                    map_check.x += step.x;
-                    distance = ray_length1d.x;
-                    ray_length1d.x += step_size.x;
-                } else {
-                    map_check.y += step.y;
-                    distance = ray_length1d.y;
-                    ray_length1d.y += step_size.y;
-                }
-                // test map to see where/if we hit
-                if map_check.x >= 0
-                    && map_check.x < self.size.x
-                    && map_check.y >= 0
-                    && map_check.y < self.size.y
-                {
-                    tile_found =
-                        self.map[(map_check.y * self.size.x + map_check.x) as usize] == b'#';
-                    let tile_midpoint = map_check.as_vec2() + 0.5;
-                    let tile_intersection = self.player.pos + ray_direction * distance;
-                    let intersect_angle = (tile_intersection.y - tile_midpoint.y).atan2(tile_intersection.x - tile_midpoint.x);
-                    if intersect_angle >= -PI * 0.25 && intersect_angle < PI * 0.25 {
-                        texture_sample_x = tile_intersection.y - map_check.y as f32;
-                    }
-                    if intersect_angle >= PI * 0.25 && intersect_angle < PI * 0.75 {
-                        texture_sample_x = tile_intersection.x - map_check.x as f32;
-                    }
-                    if intersect_angle < -PI * 0.25 && intersect_angle >= -PI * 0.75 {
-                        texture_sample_x = tile_intersection.x - map_check.x as f32;
-                    }
-                    if intersect_angle >= PI * 0.75 || intersect_angle < -PI * 0.75 {
-                        texture_sample_x = tile_intersection.y - map_check.y as f32;
-                    }
-                }
+    /// Direction of the ray through screen column `x`. The columns are
+    /// spread evenly over a flat screen rather than over the angle, so
+    /// straight walls stay straight.
+    fn ray_direction(&self, x: f32, screen_width: f32) -> Vec2 {
+        let facing = self.facing();
+        let plane = vec2(-facing.y, facing.x) * (self.player.fov / 2.).tan();
+        let camera_x = 2. * (x + 0.5) / screen_width - 1.;
+        (facing + plane * camera_x).normalize()
+    }
+
+    /// Width of a screen column at `distance` in front of the player, in tiles.
+    fn column_width(&self, distance: f32, screen_width: f32) -> f32 {
+        distance * 2. * (self.player.fov / 2.).tan() / screen_width
+    }
+
+    /// Casts the ray for screen column `x` and returns where it hit.
+    fn cast_ray(&self, x: f32, screen_width: f32) -> Column {
+        let ray_direction = self.ray_direction(x, screen_width);
+        let step_size = vec2(
+            (1. + (ray_direction.y / ray_direction.x) * (ray_direction.y / ray_direction.x))
+                .sqrt(),
+            (1. + (ray_direction.x / ray_direction.y) * (ray_direction.x / ray_direction.y))
+                .sqrt(),
+        );
+        let mut map_check = self.player.pos.as_ivec2();
+        let step = ivec2(
+            ray_direction.x.signum() as i32,
+            ray_direction.y.signum() as i32,
+        );
+        let mut ray_length1d = vec2(
+            if ray_direction.x < 0.0 {
+                (self.player.pos.x - map_check.x as f32) * step_size.x
+            } else {
+                ((map_check.x + 1) as f32 - self.player.pos.x) * step_size.x
+            },
+            if ray_direction.y < 0.0 {
+                (self.player.pos.y - map_check.y as f32) * step_size.y
+            } else {
+                ((map_check.y + 1) as f32 - self.player.pos.y) * step_size.y
+            },
+        );
+
+        let mut wall = None;
+        let mut distance = 0.0;
+        // whether the last step crossed a vertical tile edge
+        let mut x_side = false;
+
+        while wall.is_none() && distance < self.render_distance {
+            // walk shortest path
+            if ray_length1d.x < ray_length1d.y {
+                map_check.x += step.x;
+                distance = ray_length1d.x;
+                ray_length1d.x += step_size.x;
+                x_side = true;
+            } else {
+                map_check.y += step.y;
+                distance = ray_length1d.y;
+                ray_length1d.y += step_size.y;
+                x_side = false;
+            }
+            // test map to see where/if we hit
+            if (0..self.size.x).contains(&map_check.x) && (0..self.size.y).contains(&map_check.y) {
+                wall = map::wall_kind(self.map[(map_check.y * self.size.x + map_check.x) as usize]);
             }
+        }
+
+        // where along the face the ray hit, left to right as seen from the ray
+        let hit = self.player.pos + ray_direction * distance;
+        let texture_x = if x_side {
+            let u = hit.y - map_check.y as f32;
+            if ray_direction.x < 0. { 1. - u } else { u }
+        } else {
+            let u = hit.x - map_check.x as f32;
+            if ray_direction.y > 0. { 1. - u } else { u }
+        };
+        let shade = 1. - distance / self.render_distance;
+        let view_cos = ray_direction.dot(self.facing());
+        // the column gets wider on the wall the more it faces away from the ray
+        let face_cos = if x_side { ray_direction.x } else { ray_direction.y }.abs();
+        let distance = distance * view_cos;
+        Column {
+            wall,
+            distance,
+            texture_x,
+            texture_width: self.column_width(distance, screen_width) * view_cos / face_cos.max(0.01),
+            shade,
+        }
+    }
 
-            let sh = screen_height;
-            let ceil_distance = (sh / 2.) - sh / distance;
-            let floor_distance = sh - ceil_distance;
-            let c = 1. - distance / self.render_distance;
+    /// Builds the walls as one line per texel, the way they were drawn
+    /// before the software renderer. Only kept around to benchmark against.
+    fn wall_mesh(&self, screen_width: f32, screen_height: f32) -> GameResult<graphics::MeshBuilder> {
+        let mut mb = graphics::MeshBuilder::new();
+        for x in 0..screen_width as u32 {
+            let column = self.cast_ray(x as f32, screen_width);
+            let Some(wall) = column.wall else { continue };
+            let texture = self.walls.texture(wall);
+            let (ceil_distance, floor_distance) = column.wall_span(screen_height);
             let line_distance = floor_distance - ceil_distance;
 
-            for y in 0..self.wall_texture.height() {
-                let texture_sample_y = y as f32 / self.wall_texture.height() as f32;
-                let next_y = (y + 1) as f32 / self.wall_texture.height() as f32;
+            for y in 0..texture.height() {
+                let texture_sample_y = y as f32 / texture.height() as f32;
+                let next_y = (y + 1) as f32 / texture.height() as f32;
                 mb.line(
                     &[
                         vec2(x as f32, ceil_distance + texture_sample_y * line_distance),
                         vec2(x as f32, ceil_distance + next_y * line_distance),
                     ],
                     1.0,
-                    self.wall_texture.sample_color_weighted(texture_sample_x, texture_sample_y, c),
+                    texture.sample_color_weighted(
+                        column.texture_x,
+                        texture_sample_y,
+                        (column.texture_width, 1. / line_distance),
+                        column.shade,
+                    ),
                 )?;
             }
         }
-        Ok(Mesh::from_data(&ctx.gfx, mb.build()))
+        Ok(mb)
+    }
+
+    /// Renders the view of the player into `frame`, one pixel at a time,
+    /// with its rays and then its rows split evenly between the threads of
+    /// `self.pool`.
+    fn render_frame(&self, frame: &mut texture::Texture) {
+        let (width, height) = (frame.width(), frame.height());
+        let threads = self.pool.current_num_threads().clamp(1, height.max(1));
+        self.pool.install(|| {
+            let columns: Vec<Column> = (0..width)
+                .into_par_iter()
+                .map(|x| self.cast_ray(x as f32, width as f32))
+                .collect();
+            frame
+                .bands_mut(height.div_ceil(threads))
+                .par_bridge()
+                .for_each(|mut rows| self.render_rows(&mut rows, &columns, height));
+        });
+    }
+
+    /// Renders with `threads` threads from now on.
+    fn set_threads(&mut self, threads: usize) {
+        self.pool = render_pool(threads);
+    }
+
+    /// Renders `rows` of a screen `screen_height` pixels high, with one of
+    /// `columns` for each of its columns.
+    fn render_rows(&self, rows: &mut texture::Rows, columns: &[Column], screen_height: usize) {
+        let brightness = self.beat_brightness() * self.shading_brightness();
+        let range = rows.range();
+        self.render_floor_ceiling(rows, columns.len(), screen_height);
+        for (x, column) in columns.iter().enumerate() {
+            let Some(wall) = column.wall else { continue };
+            let texture = self.walls.texture(wall);
+            let (ceil_distance, floor_distance) = column.wall_span(screen_height as f32);
+            let line_distance = floor_distance - ceil_distance;
+            let top = (ceil_distance.max(0.) as usize).max(range.start);
+            let bottom = (floor_distance.min(screen_height as f32).max(0.) as usize).min(range.end);
+            for y in top..bottom {
+                let texture_sample_y = (y as f32 + 0.5 - ceil_distance) / line_distance;
+                let color = texture.sample_color_weighted(
+                    column.texture_x,
+                    texture_sample_y,
+                    (column.texture_width, 1. / line_distance),
+                    column.shade,
+                );
+                rows.set_color(x, y, brighten(color, brightness));
+            }
+        }
+    }
+
+    /// Fills `rows` of a screen `screen_width` by `screen_height` pixels with
+    /// the floor and ceiling as seen from the player. The walls are meant to
+    /// be drawn on top of it.
+    fn render_floor_ceiling(&self, rows: &mut texture::Rows, screen_width: usize, screen_height: usize) {
+        let facing = self.facing();
+        // each ray with how far it goes per unit of distance along `facing`
+        let rays: Vec<(Vec2, f32)> = (0..screen_width)
+            .map(|x| {
+                let direction = self.ray_direction(x as f32, screen_width as f32);
+                (direction, 1. / direction.dot(facing))
+            })
+            .collect();
+        let height = screen_height as f32;
+        let horizon = height / 2.;
+        for y in rows.range() {
+            // inverse of the wall projection in `Column::wall_span`
+            let offset = y as f32 + 0.5 - horizon;
+            let distance = height / offset.abs();
+            let texture = if offset > 0. { self.walls.floor() } else { self.walls.ceiling() };
+            // one row further down the screen is this much closer
+            let row_depth = distance * distance / height;
+            let footprint = (self.column_width(distance, screen_width as f32), row_depth);
+            for (x, &(direction, stretch)) in rays.iter().enumerate() {
+                let ray_distance = distance * stretch;
+                if ray_distance >= self.render_distance {
+                    rows.set_color(x, y, Color::BLACK);
+                    continue;
+                }
+                let point = self.player.pos + direction * ray_distance;
+                let (u, v) = (point.x - point.x.floor(), point.y - point.y.floor());
+                let shade = 1. - ray_distance / self.render_distance;
+                let footprint = (footprint.0, footprint.1 * stretch);
+                rows.set_color(x, y, texture.sample_color_weighted(u, v, footprint, shade));
+            }
+        }
+    }
+
+    /// Starts the beat effect for `onset`.
+    fn on_onset(&mut self, onset: onset::Onset) {
+        self.tempo.push(onset);
+        self.beat = self.beat.max(onset.strength);
+        if self.beat_effect == BeatEffect::Flash {
+            self.walls.flash(onset.strength);
+        }
+    }
+
+    /// Lets the beat effect fade for `dt` seconds.
+    fn fade_beat(&mut self, dt: f32) {
+        self.beat *= (-dt / BEAT_TIME).exp();
+        self.clock += dt;
+        self.shake = match self.beat_effect {
+            // two wobbles at odd frequencies, so it doesn't look regular
+            BeatEffect::Shake => {
+                let wobble = ((self.clock * 53.).sin() + (self.clock * 31.).sin()) / 2.;
+                wobble * self.beat * BEAT_SHAKE
+            }
+            _ => 0.,
+        };
+    }
+
+    /// Factor the walls' colors are multiplied with for the beat effect.
+    fn beat_brightness(&self) -> f32 {
+        match self.beat_effect {
+            BeatEffect::Pulse => 1. + self.beat * BEAT_BRIGHTNESS,
+            _ => 1.,
+        }
+    }
+
+    /// Factor the walls' colors are multiplied with for the shading.
+    fn shading_brightness(&self) -> f32 {
+        match self.shading {
+            Shading::Distance => 1.,
+            Shading::Loudness => {
+                let loudness = self.meter.levels().momentary;
+                let t = ((loudness - QUIET_LUFS) / (LOUD_LUFS - QUIET_LUFS)).clamp(0., 1.);
+                QUIET_BRIGHTNESS + (1. - QUIET_BRIGHTNESS) * t
+            }
+        }
+    }
+
+    /// How far into the current beat the music is, from 0 on the beat to
+    /// almost 1, for animations that go along with it. `None` until the tempo
+    /// is known.
+    fn beat_phase(&self) -> Option<f32> {
+        // the onsets were found in the dropped frames too
+        let dropped = self.audio_frames.as_ref().map_or(0, audio::VisReceiver::dropped);
+        let time = (self.frames + dropped) as f64 / self.sample_rate as f64;
+        self.tempo.tempo().map(|tempo| tempo.phase(time))
+    }
+
+    /// Makes the walls show the right amount of audio arriving at
+    /// `sample_rate`, starting over from silence.
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.walls.set_sample_rate(sample_rate);
+        self.wave_buffer = HeapRb::new(self.walls.history());
+        self.sample_rate = sample_rate;
+        self.frames = 0;
+        self.tempo = tempo::TempoTracker::new();
+        self.meter = meter::Meter::new(sample_rate);
+    }
+
+    /// Sends `command` to the audio thread, if it is still running.
+    fn send(&self, command: audio::ToAudio) {
+        // it stops early without a sound card, then there's nothing to control
+        let _ = self.tx.try_send(command);
+    }
+
+    /// Appends a single frame of audio to what is shown on the walls.
+    fn push_frame(&mut self, frame: mix::VisFrame) {
+        self.wave_buffer.push_overwrite(frame);
+        self.meter.push(frame);
+        self.frames += 1;
+    }
+
+    /// Redraws the wall textures from the current contents of the wave buffer.
+    fn update_wall_textures(&mut self) {
+        self.walls.update(&self.wave_buffer);
     }
 }
 
@@ -264,29 +573,35 @@ impl EventHandler for Game {
             new_pos = self.player.pos;
         }
         // check wall collision
-        if self.map[(new_pos.y as i32 * self.size.x + new_pos.x as i32) as usize] == b'#' {
+        if map::is_wall(self.map[(new_pos.y as i32 * self.size.x + new_pos.x as i32) as usize]) {
             self.player.pos = old_pos;
             new_pos = self.player.pos;
         }
         // update map
         if old_pos != new_pos {
-            self.map[(old_pos.y as i32 * self.size.x + old_pos.x as i32) as usize] = b'.';
+            self.map[(old_pos.y as i32 * self.size.x + old_pos.x as i32) as usize] = map::FLOOR;
             self.map[(new_pos.y as i32 * self.size.x + new_pos.x as i32) as usize] = b'P';
         }
         // update wall texture with data from audio thread
         // get new data
-        for _ in 0..WAVE_SIZE {
-            if let Ok(audio::FromAudio::Data(data)) = self.rx.try_recv() {
-                self.wave_buffer.push_overwrite(data);
-            } else {
-                break;
+        while let Ok(message) = self.rx.try_recv() {
+            match message {
+                audio::FromAudio::Started { sample_rate, frames } => {
+                    self.set_sample_rate(sample_rate);
+                    self.audio_frames = Some(frames);
+                }
+                audio::FromAudio::Onset(onset) => self.on_onset(onset),
+                audio::FromAudio::Status(status) => eprintln!("{}", status),
             }
         }
-        self.wall_texture.clear();
-        // move data into texture
-        for (i, val) in self.wave_buffer.iter().enumerate() {
-            self.wall_texture.set_color(i, ((val.clamp(-1.,1.) + 1.) * WAVE_MUL) as usize, Color::BLACK);
+        if let Some(mut frames) = self.audio_frames.take() {
+            while let Some(frame) = frames.recv() {
+                self.push_frame(frame);
+            }
+            self.audio_frames = Some(frames);
         }
+        self.fade_beat(delta_time);
+        self.update_wall_textures();
         Ok(())
     }
 
@@ -331,11 +646,54 @@ impl EventHandler for Game {
                 KeyCode::W | KeyCode::S => self.player.controller.y = 0,
                 KeyCode::Left | KeyCode::Right => self.player.controller.a = 0,
                 KeyCode::M => self.draw_map = !self.draw_map,
+                KeyCode::F => eprintln!("walls show {:?}", self.walls.cycle_mode()),
+                KeyCode::K => eprintln!("{:?} colors", self.walls.cycle_colormap()),
+                KeyCode::B => {
+                    self.beat_effect = self.beat_effect.next();
+                    eprintln!("beat effect {:?}", self.beat_effect);
+                }
+                KeyCode::Minus => eprintln!("spectrum range {:?} dB", self.walls.shift_min_db(false)),
+                KeyCode::Equals => eprintln!("spectrum range {:?} dB", self.walls.shift_min_db(true)),
+                KeyCode::LBracket => eprintln!("spectrum range {:?} dB", self.walls.shift_max_db(false)),
+                KeyCode::RBracket => eprintln!("spectrum range {:?} dB", self.walls.shift_max_db(true)),
                 KeyCode::T => { 
-                    self.tx.send(audio::ToAudio::ToggleVisuals).expect("send command to audio thread")
+                    self.send(audio::ToAudio::ToggleVisuals)
                 },
                 KeyCode::R => {
-                    self.tx.send(audio::ToAudio::ToggleMic).expect("send command to audio thread")
+                    self.send(audio::ToAudio::ToggleMic)
+                }
+                KeyCode::C => {
+                    self.send(audio::ToAudio::CycleVisChannel)
+                }
+                KeyCode::N => {
+                    self.send(audio::ToAudio::NextTrack)
+                }
+                KeyCode::P => {
+                    self.send(audio::ToAudio::PreviousTrack)
+                }
+                KeyCode::Z => {
+                    self.send(audio::ToAudio::ToggleShuffle)
+                }
+                KeyCode::X => {
+                    self.send(audio::ToAudio::CycleRepeat)
+                }
+                KeyCode::Space => {
+                    self.send(audio::ToAudio::TogglePause)
+                }
+                KeyCode::Comma => {
+                    self.send(audio::ToAudio::Seek(-SEEK_SECONDS))
+                }
+                KeyCode::Period => {
+                    self.send(audio::ToAudio::Seek(SEEK_SECONDS))
+                }
+                KeyCode::Home => {
+                    self.send(audio::ToAudio::Restart)
+                }
+                KeyCode::L => {
+                    self.send(audio::ToAudio::CycleLoop)
+                }
+                KeyCode::Q => {
+                    self.send(audio::ToAudio::CycleResampleQuality)
                 }
                 _ => {}
             }
@@ -345,8 +703,26 @@ impl EventHandler for Game {
 
     fn draw(&mut self, ctx: &mut Context) -> GameResult<()> {
         let mut canvas = graphics::Canvas::from_frame(ctx, Color::BLACK);
-        let mesh = self.raycast(&ctx)?;
-        canvas.draw(&mesh, DrawParam::default());
+
+        let (screen_width, screen_height) = ctx.gfx.drawable_size();
+        if screen_width < 1. || screen_height < 1. {
+            // minimized, and an empty image can't be made
+            return canvas.finish(ctx);
+        }
+        let mut frame = std::mem::take(&mut self.frame);
+        if (frame.width(), frame.height()) != (screen_width as usize, screen_height as usize) {
+            frame = texture::Texture::new(screen_width as usize, screen_height as usize);
+        }
+        self.render_frame(&mut frame);
+        let image = graphics::Image::from_pixels(
+            ctx,
+            &frame.to_rgba8(),
+            graphics::ImageFormat::Rgba8UnormSrgb,
+            screen_width as u32,
+            screen_height as u32,
+        );
+        self.frame = frame;
+        canvas.draw(&image, DrawParam::default());
 
         let mut y = 20.0;
         if self.draw_map {
@@ -362,7 +738,7 @@ impl EventHandler for Game {
                     &t,
                     DrawParam::default().dest(vec2(20., y)).color(Color::WHITE),
                 );
-                y += t.dimensions(ctx).unwrap_or(graphics::Rect::default()).h;
+                y += t.dimensions(ctx).unwrap_or_default().h;
             }
         }
         let fps_txt = Text::new(ctx.time.fps().to_string() + " fps");
@@ -370,35 +746,320 @@ impl EventHandler for Game {
             &fps_txt,
             DrawParam::default().dest(vec2(20., y)).color(Color::WHITE),
         );
+        if let (Some(tempo), Some(phase)) = (self.tempo.tempo(), self.beat_phase()) {
+            let bpm_txt = Text::new(format!("{:.0} bpm", tempo.bpm));
+            let x = 30. + fps_txt.dimensions(ctx).unwrap_or_default().w;
+            // turns yellow on every beat
+            let glow = 1. - phase;
+            canvas.draw(
+                &bpm_txt,
+                DrawParam::default().dest(vec2(x, y)).color(Color::new(1., 1., 1. - glow, 1.)),
+            );
+        }
+        y += fps_txt.dimensions(ctx).unwrap_or_default().h;
+        let levels = self.meter.levels();
+        let meter_txt = Text::new(format!(
+            "RMS {:.1} dBFS  peak {:.1} dBTP\nM {:.1} LUFS  S {:.1} LUFS",
+            levels.rms, levels.true_peak, levels.momentary, levels.short_term,
+        ));
+        canvas.draw(
+            &meter_txt,
+            DrawParam::default().dest(vec2(20., y)).color(Color::WHITE),
+        );
+        if let Some(counters) = self.audio_frames.as_ref().map(audio::VisReceiver::counters) {
+            let load = |counter: &std::sync::atomic::AtomicU64| counter.load(std::sync::atomic::Ordering::Relaxed);
+            let (frames, commands) = (load(&counters.frames_dropped), load(&counters.commands_dropped));
+            let (overflows, underflows) = (load(&counters.mic_overflows), load(&counters.mic_underflows));
+            if frames + commands + overflows + underflows > 0 {
+                y += meter_txt.dimensions(ctx).unwrap_or_default().h;
+                let counters_txt = Text::new(format!(
+                    "dropped {} frames, {} keys\nmic {} overflows, {} underflows",
+                    frames, commands, overflows, underflows,
+                ));
+                canvas.draw(
+                    &counters_txt,
+                    DrawParam::default().dest(vec2(20., y)).color(Color::WHITE),
+                );
+            }
+        }
 
         canvas.finish(ctx)?;
         timer::yield_now();
         Ok(())
     }
 
-    fn on_error(&mut self, _ctx: &mut Context, origin: event::ErrorOrigin, e: GameError) -> bool {
-        match origin {
-            event::ErrorOrigin::Draw => match e {
-                GameError::LyonError(_s) => false,
-                _ => true,
-            },
-            _ => true,
-        }
+    fn quit_event(&mut self, _ctx: &mut Context) -> GameResult<bool> {
+        // stop the audio before the window goes, rather than have the
+        // process end under it
+        self.audio = None;
+        Ok(false)
     }
 
 }
 
+/// `color` with its red, green and blue times `factor`. Its alpha stays, so
+/// it looks `factor` times as bright over black.
+fn brighten(color: Color, factor: f32) -> Color {
+    Color::new(color.r * factor, color.g * factor, color.b * factor, color.a)
+}
+
+/// Threads to render with, `threads` of them or one if they can't be
+/// started.
+fn render_pool(threads: usize) -> rayon::ThreadPool {
+    let pool = |threads| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("render {}", i))
+            .build()
+    };
+    pool(threads).or_else(|_| pool(1)).expect("no thread to render with")
+}
+
 fn main() {
+    let args = cli::Args::parse_valid();
+    let map = match args.load_map() {
+        Ok(map) => map,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(1);
+        }
+    };
+    if args.bench {
+        bench::run(&args, map);
+        return;
+    }
+    if let Some(out_dir) = &args.render {
+        if let Err(e) = headless::render(&args, map, out_dir) {
+            eprintln!("render failed: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let window_mode = WindowMode::default()
+        .dimensions(args.width as f32, args.height as f32)
+        .resizable(true);
+    let window_mode = if args.fullscreen {
+        window_mode.fullscreen_type(FullscreenType::Desktop)
+    } else {
+        window_mode
+    };
     let (ctx, ev_loop) = ContextBuilder::new("DD2258 Bonus Project", "Day")
         .window_setup(WindowSetup::default().title("DD2258 Bonus Project"))
-        .window_mode(
-            WindowMode::default()
-                .dimensions(1280., 720.)
-                .resizable(true),
-        )
+        .window_mode(window_mode)
         .build()
         .expect("get context");
-    let (tx, rx) = audio::audio_thread();
-    let game = Game::new(16, 16, 20., tx, rx);
+    let (tx, rx, audio) = audio::audio_thread(audio::Options {
+        music: args.music.clone(),
+        latency: args.latency,
+        quality: args.resample,
+        use_mic: args.mode == cli::Mode::Mic,
+        source: args.source,
+    });
+    let mut game = Game::new(map, args.render_distance, args.fov.to_radians(), args.wall_settings(), args.render_threads(), tx, rx);
+    game.beat_effect = args.beat;
+    game.shading = args.shading;
+    game.audio = Some(audio);
     event::run(ctx, ev_loop, game);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    const WIDTH: u32 = 96;
+    const HEIGHT: u32 = 64;
+    /// Channel difference that still counts as the same color.
+    const TOLERANCE: u8 = 2;
+    /// Fraction of pixels that may differ, for edges landing one pixel over
+    /// on other platforms.
+    const MAX_DIFFERENT: f32 = 0.005;
+
+    const ROOM: &str = "\
+width = 8
+height = 6
+spawn = 2.5 2.5
+direction = 0
+
+########
+#......#
+#......S
+#..T...#
+#......#
+###V####
+";
+
+    fn game(x: f32, y: f32, degrees: f32) -> Game {
+        let (tx, _) = crossbeam_channel::bounded(1);
+        let (_, rx) = crossbeam_channel::bounded(1);
+        let settings = walls::Settings {
+            mode: walls::WaveMode::Waveform,
+            colormap: colormap::ColorMap::default(),
+            min_db: -72.0,
+            max_db: 0.0,
+            wave: walls::WaveStyle::default(),
+            texture_width: 4410,
+            texture_height: 32,
+            window_ms: 100.0,
+        };
+        let map = map::Map::parse(ROOM).unwrap();
+        let mut game = Game::new(map, 20.0, 90f32.to_radians(), settings, 1, tx, rx);
+        game.player.pos = vec2(x, y);
+        game.player.angle = degrees.to_radians();
+        let mut tone = source::Tone::new(440.0, 0.5, 44100);
+        game.set_sample_rate(44100);
+        for _ in 0..game.wave_buffer.capacity() {
+            let sample = source::Source::next_sample(&mut tone).unwrap_or(0.0);
+            game.push_frame(mix::VisFrame::new(&[sample], mix::VisChannel::Mix));
+        }
+        game.update_wall_textures();
+        game
+    }
+
+    /// Renders `game` and compares it with `tests/golden/<name>.png`, or
+    /// writes that file when `UPDATE_GOLDEN` is set.
+    fn check_golden(game: &Game, name: &str) {
+        let mut frame = texture::Texture::new(WIDTH as usize, HEIGHT as usize);
+        game.render_frame(&mut frame);
+        let actual = image::RgbaImage::from_raw(WIDTH, HEIGHT, frame.to_rgba8()).unwrap();
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(format!("tests/golden/{}.png", name));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            actual.save(&path).unwrap();
+            return;
+        }
+        let expected = image::open(&path)
+            .unwrap_or_else(|e| panic!("{}: {} (run with UPDATE_GOLDEN=1 to create it)", path.display(), e))
+            .into_rgba8();
+        assert_eq!(expected.dimensions(), actual.dimensions(), "{}", name);
+        let different = expected
+            .pixels()
+            .zip(actual.pixels())
+            .filter(|(e, a)| e.0.iter().zip(a.0).any(|(e, a)| e.abs_diff(a) > TOLERANCE))
+            .count();
+        assert!(
+            different as f32 <= MAX_DIFFERENT * (WIDTH * HEIGHT) as f32,
+            "{}: {} pixels differ from {}",
+            name,
+            different,
+            path.display()
+        );
+    }
+
+    #[test]
+    fn golden_facing_wall() {
+        check_golden(&game(2.5, 2.5, 0.0), "facing_wall");
+    }
+
+    #[test]
+    fn golden_corner() {
+        check_golden(&game(5.5, 3.5, -45.0), "corner");
+    }
+
+    #[test]
+    fn golden_pillar() {
+        check_golden(&game(5.5, 4.5, 200.0), "pillar");
+    }
+
+    #[test]
+    fn golden_looking_down_room() {
+        check_golden(&game(1.5, 1.5, 30.0), "looking_down_room");
+    }
+
+    #[test]
+    fn threads_render_the_same_frame_as_one() {
+        // 61 rows don't split evenly between any of these
+        let (width, height) = (97, 61);
+        let mut game = game(5.5, 3.5, -45.0);
+        let mut one = texture::Texture::new(width, height);
+        game.render_frame(&mut one);
+        for threads in [2, 3, 7, 64] {
+            game.set_threads(threads);
+            let mut many = texture::Texture::new(width, height);
+            game.render_frame(&mut many);
+            for y in 0..height {
+                for x in 0..width {
+                    assert_eq!(one.get_color(x, y), many.get_color(x, y), "{} threads at {}, {}", threads, x, y);
+                }
+            }
+        }
+    }
+
+    /// `game` rendered at `WIDTH` by `HEIGHT`, as it looks over black.
+    fn drawn(game: &Game) -> Vec<[f32; 3]> {
+        let mut frame = texture::Texture::new(WIDTH as usize, HEIGHT as usize);
+        game.render_frame(&mut frame);
+        let mut pixels = Vec::new();
+        for y in 0..frame.height() {
+            for x in 0..frame.width() {
+                let c = frame.get_color(x, y);
+                pixels.push([c.r * c.a, c.g * c.a, c.b * c.a]);
+            }
+        }
+        pixels
+    }
+
+    /// Checks every pixel of `bright` is `factor` times as bright as the
+    /// one in `plain`, or the same where nothing brightens it.
+    fn assert_brighter(plain: &[[f32; 3]], bright: &[[f32; 3]], factor: f32) {
+        let mut brightened = 0;
+        for (p, b) in plain.iter().zip(bright) {
+            for (p, b) in p.iter().zip(b) {
+                if (p - b).abs() > 1e-6 {
+                    assert!((p * factor - b).abs() < 1e-5, "{} is not {} times {}", b, factor, p);
+                    brightened += 1;
+                }
+            }
+        }
+        assert!(brightened > 0);
+    }
+
+    #[test]
+    fn pulse_brightens_the_walls_by_the_beat() {
+        let mut game = game(2.5, 2.5, 0.0);
+        game.beat_effect = BeatEffect::Pulse;
+        let plain = drawn(&game);
+        game.beat = 0.5;
+        assert_brighter(&plain, &drawn(&game), 1. + 0.5 * BEAT_BRIGHTNESS);
+    }
+
+    #[test]
+    fn quiet_loudness_shading_dims_the_walls_to_its_brightness() {
+        let mut game = game(2.5, 2.5, 0.0);
+        game.beat_effect = BeatEffect::None;
+        let plain = drawn(&game);
+        // nothing metered is as quiet as it gets
+        game.meter = meter::Meter::new(44100);
+        game.shading = Shading::Loudness;
+        assert_brighter(&plain, &drawn(&game), QUIET_BRIGHTNESS);
+    }
+
+    #[test]
+    fn head_on_wall_is_flat() {
+        let game = game(1.5, 1.5, 0.0);
+        let mut checked = 0;
+        for x in 0..WIDTH {
+            let column = game.cast_ray(x as f32, WIDTH as f32);
+            let direction = game.ray_direction(x as f32, WIDTH as f32);
+            let hit = game.player.pos + direction * column.distance / direction.dot(game.facing());
+            // only the rays that made it to the far wall
+            if (hit.x - 7.0).abs() < 1e-3 {
+                assert!((column.distance - 5.5).abs() < 1e-4, "column {}: {}", x, column.distance);
+                checked += 1;
+            }
+        }
+        assert!(checked > WIDTH / 4, "only {} columns reached the far wall", checked);
+    }
+
+    #[test]
+    fn texture_runs_left_to_right_on_every_face() {
+        // standing in the middle of the room and turning around, the middle
+        // ray should hit each face further to the right when aimed a bit
+        // further right
+        for degrees in [0.0, 90.0, 180.0, 270.0] {
+            let game = game(4.5, 2.5, degrees);
+            let left = game.cast_ray(WIDTH as f32 / 2. - 4., WIDTH as f32);
+            let right = game.cast_ray(WIDTH as f32 / 2. + 4., WIDTH as f32);
+            assert!(left.texture_x < right.texture_x, "facing {}: {} >= {}", degrees, left.texture_x, right.texture_x);
+        }
+    }
+}
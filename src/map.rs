@@ -0,0 +1,274 @@
+//! Levels, loaded from text files like the ones in `./maps`.
+//!
+//! A map file starts with a header of `key = value` lines, followed by a
+//! blank line and then the tiles, one row per line:
+//!
+//! ```text
+//! ; lines starting with ';' are comments
+//! width = 4
+//! height = 3
+//! spawn = 1.5 1.5
+//! direction = 0
+//!
+//! ####
+//! #..#
+//! ####
+//! ```
+//!
+//! `spawn` is where the player starts, in tiles from the top left corner, and
+//! `direction` is the way they face in degrees clockwise from the +x axis.
+//! `.` is open floor and every other tile is a kind of wall:
+//!
+//! - `#` the waveform of the music
+//! - `S` its frequency spectrum
+//! - `V` VU meters for the left and right channel
+//! - `T` a solid color that gets brighter with the volume
+//! - `C` the waveforms of the left and right channel, one above the other
+//!
+//! The map has to be closed off by walls all the way around its border.
+
+use anyhow::{bail, ensure, Context};
+use ggez::glam::{vec2, Vec2};
+use std::path::Path;
+
+pub const FLOOR: u8 = b'.';
+
+/// Most tiles a map can have, so a typo in the header doesn't allocate
+/// gigabytes.
+const MAX_TILES: i32 = 1 << 20;
+
+/// The map that is used when no other one is given.
+pub const DEFAULT: &str = include_str!("../maps/default.map");
+
+pub struct Map {
+    pub width: i32,
+    pub height: i32,
+    /// Tiles row by row, `width * height` of them.
+    pub tiles: Vec<u8>,
+    pub spawn: Vec2,
+    /// Direction the player faces at the start, in radians.
+    pub direction: f32,
+}
+
+/// The different textures a wall can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WallKind {
+    Waveform,
+    Spectrum,
+    Vu,
+    Tint,
+    Channels,
+}
+
+pub fn wall_kind(tile: u8) -> Option<WallKind> {
+    match tile {
+        b'#' => Some(WallKind::Waveform),
+        b'S' => Some(WallKind::Spectrum),
+        b'V' => Some(WallKind::Vu),
+        b'T' => Some(WallKind::Tint),
+        b'C' => Some(WallKind::Channels),
+        _ => None,
+    }
+}
+
+pub fn is_wall(tile: u8) -> bool {
+    wall_kind(tile).is_some()
+}
+
+impl Map {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("load map {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut lines = text.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+        let (mut width, mut height, mut spawn, mut direction) = (None, None, None, 0.0);
+        for (n, line) in lines.by_ref() {
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if line.starts_with(';') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("line {}: expected `key = value`, found `{}`", n, line);
+            };
+            let value = value.trim();
+            match key.trim() {
+                "width" => width = Some(parse_number::<i32>(value, n)?),
+                "height" => height = Some(parse_number::<i32>(value, n)?),
+                "spawn" => {
+                    let coords = value
+                        .split_whitespace()
+                        .map(|v| parse_number::<f32>(v, n))
+                        .collect::<anyhow::Result<Vec<_>>>()?;
+                    ensure!(coords.len() == 2, "line {}: spawn needs an x and a y coordinate", n);
+                    spawn = Some(vec2(coords[0], coords[1]));
+                }
+                "direction" => direction = parse_number::<f32>(value, n)?.to_radians(),
+                key => bail!("line {}: unknown setting `{}`", n, key),
+            }
+        }
+        let width = width.context("missing `width`")?;
+        let height = height.context("missing `height`")?;
+        let spawn = spawn.context("missing `spawn`")?;
+        ensure!(width > 0 && height > 0, "the map must be at least one tile wide and high");
+        let size = width
+            .checked_mul(height)
+            .filter(|&size| size <= MAX_TILES)
+            .with_context(|| format!("a {}x{} map is too big, it can have at most {} tiles", width, height, MAX_TILES))?;
+
+        let mut tiles = Vec::with_capacity(size as usize);
+        let mut rows = 0;
+        for (n, row) in lines {
+            let row = row.trim_end();
+            if row.is_empty() {
+                continue;
+            }
+            ensure!(rows < height, "line {}: more than {} rows", n, height);
+            ensure!(
+                row.len() == width as usize,
+                "line {}: row is {} tiles wide, expected {}",
+                n,
+                row.len(),
+                width
+            );
+            for (x, tile) in row.bytes().enumerate() {
+                ensure!(
+                    tile == FLOOR || is_wall(tile),
+                    "line {}: unknown tile `{}` in column {}",
+                    n,
+                    tile as char,
+                    x + 1
+                );
+                let on_border = rows == 0 || rows == height - 1 || x == 0 || x == width as usize - 1;
+                ensure!(
+                    !on_border || is_wall(tile),
+                    "line {}: the map is open at ({}, {}), the border must be all walls",
+                    n,
+                    x,
+                    rows
+                );
+            }
+            tiles.extend(row.bytes());
+            rows += 1;
+        }
+        ensure!(rows == height, "expected {} rows, found {}", height, rows);
+
+        ensure!(
+            (0.0..width as f32).contains(&spawn.x) && (0.0..height as f32).contains(&spawn.y),
+            "spawn ({}, {}) is outside the map",
+            spawn.x,
+            spawn.y
+        );
+        let spawn_tile = tiles[spawn.y as usize * width as usize + spawn.x as usize];
+        ensure!(!is_wall(spawn_tile), "spawn ({}, {}) is inside a wall", spawn.x, spawn.y);
+
+        Ok(Self {
+            width,
+            height,
+            tiles,
+            spawn,
+            direction,
+        })
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, line: usize) -> anyhow::Result<T> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("line {}: `{}` is not a valid number", line, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str = "width = 4\nheight = 3\nspawn = 1.5 1.5\n\n";
+
+    /// The error `text` fails to parse with.
+    fn error(text: &str) -> String {
+        match Map::parse(text) {
+            Ok(_) => panic!("parsed:\n{}", text),
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    #[test]
+    fn parses_header_and_tiles() {
+        let map = Map::parse("; a comment\nwidth = 4\nheight = 3\nspawn = 2.5 1.5\ndirection = 90\n\n####\n#..S\n#VTC\n").unwrap();
+        assert_eq!((map.width, map.height), (4, 3));
+        assert_eq!(map.tiles, b"####\n#..S\n#VTC".iter().copied().filter(|&t| t != b'\n').collect::<Vec<_>>());
+        assert_eq!(map.spawn, vec2(2.5, 1.5));
+        assert!((map.direction - std::f32::consts::FRAC_PI_2).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bundled_maps_are_valid() {
+        Map::parse(DEFAULT).unwrap();
+        for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/maps")).unwrap() {
+            let path = entry.unwrap().path();
+            Map::load(&path).unwrap_or_else(|e| panic!("{:#}", e));
+        }
+    }
+
+    #[test]
+    fn missing_settings() {
+        assert!(error("height = 3\nspawn = 1.5 1.5\n\n####").contains("missing `width`"));
+        assert!(error("width = 4\nspawn = 1.5 1.5\n\n####").contains("missing `height`"));
+        assert!(error("width = 4\nheight = 3\n\n####").contains("missing `spawn`"));
+    }
+
+    #[test]
+    fn bad_header_lines() {
+        assert!(error("width 4\n").contains("line 1: expected `key = value`"));
+        assert!(error("width = 4\nsize = 3\n").contains("line 2: unknown setting `size`"));
+        assert!(error("width = four\n").contains("line 1: `four` is not a valid number"));
+        assert!(error("spawn = 1\n").contains("spawn needs an x and a y"));
+        assert!(error("width = 0\nheight = 3\nspawn = 1 1\n\n").contains("at least one tile"));
+    }
+
+    #[test]
+    fn huge_maps_are_rejected_before_allocating() {
+        let text = "width = 50000\nheight = 50000\nspawn = 1.5 1.5\n\n";
+        assert!(error(text).contains("too big"));
+        let text = "width = 2147483647\nheight = 2\nspawn = 1.5 1.5\n\n";
+        assert!(error(text).contains("too big"));
+    }
+
+    #[test]
+    fn rows_must_be_rectangular() {
+        let ragged = format!("{}####\n#..#\n###\n", HEADER);
+        assert!(error(&ragged).contains("line 7: row is 3 tiles wide, expected 4"));
+        let short = format!("{}####\n####\n", HEADER);
+        assert!(error(&short).contains("expected 3 rows, found 2"));
+        let long = format!("{}####\n#..#\n####\n####\n", HEADER);
+        assert!(error(&long).contains("line 8: more than 3 rows"));
+    }
+
+    #[test]
+    fn unknown_tiles_are_rejected() {
+        let text = format!("{}####\n#.x#\n####\n", HEADER);
+        assert!(error(&text).contains("line 6: unknown tile `x` in column 3"));
+    }
+
+    #[test]
+    fn border_must_be_closed() {
+        let text = format!("{}####\n#...\n####\n", HEADER);
+        assert!(error(&text).contains("line 6: the map is open at (3, 1)"));
+        let text = format!("{}##.#\n#..#\n####\n", HEADER);
+        assert!(error(&text).contains("open at (2, 0)"));
+    }
+
+    #[test]
+    fn spawn_must_be_on_open_floor() {
+        let text = "width = 4\nheight = 3\nspawn = 1.5 1.5\n\n####\n#T.#\n####\n";
+        assert!(error(text).contains("spawn (1.5, 1.5) is inside a wall"));
+        let text = "width = 4\nheight = 3\nspawn = 4.5 1.5\n\n####\n#..#\n####\n";
+        assert!(error(text).contains("spawn (4.5, 1.5) is outside the map"));
+    }
+}
@@ -0,0 +1,271 @@
+//! Level meters: RMS, true peak and EBU R128 loudness of a stereo signal.
+
+use crate::mix::VisFrame;
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Seconds of audio in each block the meters are updated with.
+const BLOCK_SECONDS: f64 = 0.1;
+/// Blocks the RMS is taken over.
+const RMS_BLOCKS: usize = 3;
+/// Blocks in the momentary loudness, 400 ms.
+const MOMENTARY_BLOCKS: usize = 4;
+/// Blocks in the short-term loudness, 3 s. The peak is held as long.
+const SHORT_TERM_BLOCKS: usize = 30;
+/// Times the signal is oversampled to find peaks between samples.
+const OVERSAMPLING: usize = 4;
+/// Taps of the interpolation filter for each of the oversampled positions.
+const TAPS: usize = 12;
+
+/// What the meters read, in dB. Silence reads negative infinity.
+#[derive(Debug, Clone, Copy)]
+pub struct Levels {
+    /// Average power over the last 300 ms in dBFS, where a full scale sine
+    /// wave reads -3.
+    pub rms: f32,
+    /// Highest peak of the last 3 s in dBTP, including peaks between samples.
+    pub true_peak: f32,
+    /// Loudness of the last 400 ms, in LUFS.
+    pub momentary: f32,
+    /// Loudness of the last 3 s, in LUFS.
+    pub short_term: f32,
+}
+
+impl Default for Levels {
+    fn default() -> Self {
+        Self {
+            rms: f32::NEG_INFINITY,
+            true_peak: f32::NEG_INFINITY,
+            momentary: f32::NEG_INFINITY,
+            short_term: f32::NEG_INFINITY,
+        }
+    }
+}
+
+/// Second order IIR filter, in transposed direct form II.
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    /// Feedback coefficients, without the leading 1.
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, state: [0.0; 2] }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+/// The K-weighting of ITU-R BS.1770 for `sample_rate`: a shelf boosting the
+/// highs by 4 dB, then a high pass at 38 Hz.
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+
+    let k = (PI * 1681.974450955533 / rate).tan();
+    let q = 0.7071752369554196;
+    let vh = 10f64.powf(3.999843853973347 / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let k = (PI * 38.13547087602444 / rate).tan();
+    let q = 0.5003270373238773;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    [shelf, high_pass]
+}
+
+/// Windowed sinc filter interpolating `OVERSAMPLING - 1` samples between
+/// each pair, stored by position between the samples.
+fn interpolation_filter() -> Vec<[f32; TAPS]> {
+    let len = OVERSAMPLING * TAPS;
+    let center = (len - 1) as f64 / 2.0;
+    let mut phases = vec![[0.0; TAPS]; OVERSAMPLING];
+    for i in 0..len {
+        let t = (i as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if t == 0.0 { 1.0 } else { (PI * t).sin() / (PI * t) };
+        let window = 0.5 - 0.5 * (2.0 * PI * (i as f64 + 0.5) / len as f64).cos();
+        phases[i % OVERSAMPLING][i / OVERSAMPLING] = (sinc * window) as f32;
+    }
+    phases
+}
+
+/// One channel's filters.
+#[derive(Debug, Clone)]
+struct Channel {
+    k_weighting: [Biquad; 2],
+    /// The last `TAPS` samples, newest first, for the oversampling.
+    recent: [f32; TAPS],
+}
+
+impl Channel {
+    /// Feeds `sample` through, returning it K-weighted and the highest
+    /// oversampled peak since the previous sample.
+    fn process(&mut self, sample: f32, filter: &[[f32; TAPS]]) -> (f64, f32) {
+        let weighted = self.k_weighting.iter_mut().fold(sample as f64, |x, stage| stage.process(x));
+        self.recent.copy_within(..TAPS - 1, 1);
+        self.recent[0] = sample;
+        let peak = filter
+            .iter()
+            .map(|phase| phase.iter().zip(&self.recent).map(|(h, x)| h * x).sum::<f32>().abs())
+            .fold(sample.abs(), f32::max);
+        (weighted, peak)
+    }
+}
+
+/// Sums of one block of samples.
+#[derive(Debug, Clone, Copy, Default)]
+struct Block {
+    /// Mean square of the samples, averaged over the channels.
+    power: f64,
+    /// Mean square of the K-weighted samples, summed over the channels.
+    loudness: f64,
+    peak: f32,
+}
+
+/// Measures the levels of the frames pushed into it.
+pub struct Meter {
+    channels: [Channel; 2],
+    filter: Vec<[f32; TAPS]>,
+    block_len: usize,
+    /// The block being filled, as sums rather than means.
+    current: Block,
+    filled: usize,
+    /// Finished blocks, newest last.
+    blocks: VecDeque<Block>,
+    levels: Levels,
+}
+
+impl Meter {
+    pub fn new(sample_rate: u32) -> Self {
+        let channel = Channel { k_weighting: k_weighting(sample_rate), recent: [0.0; TAPS] };
+        Self {
+            channels: [channel.clone(), channel],
+            filter: interpolation_filter(),
+            block_len: ((sample_rate as f64 * BLOCK_SECONDS) as usize).max(1),
+            current: Block::default(),
+            filled: 0,
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+            levels: Levels::default(),
+        }
+    }
+
+    /// The levels as of the last finished block.
+    pub fn levels(&self) -> Levels {
+        self.levels
+    }
+
+    pub fn push(&mut self, frame: VisFrame) {
+        for (channel, sample) in self.channels.iter_mut().zip([frame.left, frame.right]) {
+            let (weighted, peak) = channel.process(sample, &self.filter);
+            self.current.power += (sample as f64).powi(2) / 2.0;
+            self.current.loudness += weighted * weighted;
+            self.current.peak = self.current.peak.max(peak);
+        }
+        self.filled += 1;
+        if self.filled == self.block_len {
+            self.finish_block();
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let len = self.block_len as f64;
+        let block = Block {
+            power: self.current.power / len,
+            loudness: self.current.loudness / len,
+            peak: self.current.peak,
+        };
+        self.current = Block::default();
+        self.filled = 0;
+        if self.blocks.len() == SHORT_TERM_BLOCKS {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(block);
+
+        let mean = |blocks: usize, value: fn(&Block) -> f64| {
+            self.blocks.iter().rev().take(blocks).map(value).sum::<f64>() / blocks as f64
+        };
+        self.levels = Levels {
+            rms: decibels(mean(RMS_BLOCKS, |b| b.power)),
+            true_peak: 20.0 * self.blocks.iter().map(|b| b.peak).fold(0.0, f32::max).log10(),
+            momentary: loudness(mean(MOMENTARY_BLOCKS, |b| b.loudness)),
+            short_term: loudness(mean(SHORT_TERM_BLOCKS, |b| b.loudness)),
+        };
+    }
+}
+
+fn decibels(power: f64) -> f32 {
+    (10.0 * power.log10()) as f32
+}
+
+/// Loudness in LUFS of a K-weighted mean square.
+fn loudness(power: f64) -> f32 {
+    -0.691 + decibels(power)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// Meters `seconds` of a sine wave at `frequency` Hz in both channels.
+    fn sine(frequency: f64, amplitude: f32, phase: f64, seconds: f64) -> Levels {
+        let mut meter = Meter::new(RATE);
+        for i in 0..(seconds * RATE as f64) as usize {
+            let t = i as f64 / RATE as f64;
+            let sample = amplitude * (2.0 * PI * frequency * t + phase).sin() as f32;
+            meter.push(VisFrame { mono: sample, left: sample, right: sample });
+        }
+        meter.levels()
+    }
+
+    #[test]
+    fn silence_reads_negative_infinity() {
+        let levels = sine(1000.0, 0.0, 0.0, 1.0);
+        assert_eq!(levels.rms, f32::NEG_INFINITY);
+        assert_eq!(levels.true_peak, f32::NEG_INFINITY);
+        assert_eq!(levels.momentary, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn sine_at_1khz_reads_its_level() {
+        // BS.1770 calibrates a full scale 1 kHz sine in both channels to 0 LUFS
+        let levels = sine(997.0, 0.1, 0.0, 4.0);
+        assert!((levels.momentary + 20.0).abs() < 0.1, "{}", levels.momentary);
+        assert!((levels.short_term + 20.0).abs() < 0.1, "{}", levels.short_term);
+        assert!((levels.rms + 23.01).abs() < 0.05, "{}", levels.rms);
+        assert!((levels.true_peak + 20.0).abs() < 0.1, "{}", levels.true_peak);
+    }
+
+    #[test]
+    fn low_frequencies_count_less() {
+        let low = sine(40.0, 0.1, 0.0, 1.0);
+        let high = sine(4000.0, 0.1, 0.0, 1.0);
+        assert!(low.momentary < -22.0, "{}", low.momentary);
+        assert!(high.momentary > -20.0, "{}", high.momentary);
+        assert!((low.rms - high.rms).abs() < 0.1);
+    }
+
+    #[test]
+    fn finds_peaks_between_samples() {
+        // at a quarter of the sample rate and 45 degrees off, every sample
+        // misses the peaks by 3 dB
+        let levels = sine(RATE as f64 / 4.0, 1.0, PI / 4.0, 0.5);
+        assert!(levels.true_peak > -0.5, "{}", levels.true_peak);
+    }
+}
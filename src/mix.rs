@@ -0,0 +1,169 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+
+/// Maps one frame from the source's channel layout onto the device's.
+///
+/// Channels are assumed to be in the usual wav order (front left, front
+/// right, center, LFE, surround left, surround right, ...).
+pub fn remix(input: &[f32], output: &mut [f32]) {
+    match (input.len(), output.len()) {
+        (i, o) if i == o => output.copy_from_slice(input),
+        (_, 1) => output[0] = input.iter().sum::<f32>() / input.len() as f32,
+        (1, _) => {
+            // mono goes to the front pair, surround speakers stay quiet
+            output.fill(0.0);
+            output[..2].fill(input[0]);
+        }
+        (6, 2) => {
+            // 5.1 to stereo, dropping the LFE channel
+            let (center, sl, sr) = (input[2], input[4], input[5]);
+            let norm = 1.0 / (1.0 + 2.0 * FRAC_1_SQRT_2);
+            output[0] = (input[0] + FRAC_1_SQRT_2 * (center + sl)) * norm;
+            output[1] = (input[1] + FRAC_1_SQRT_2 * (center + sr)) * norm;
+        }
+        (i, o) if i < o => {
+            output[..i].copy_from_slice(input);
+            output[i..].fill(0.0);
+        }
+        (i, o) => {
+            // fold the extra channels back onto the ones the device has
+            output.fill(0.0);
+            for (ch, sample) in input.iter().enumerate() {
+                output[ch % o] += sample;
+            }
+            let folds = i.div_ceil(o) as f32;
+            output.iter_mut().for_each(|s| *s /= folds);
+        }
+    }
+}
+
+/// What part of the signal is sent to the visualizer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VisChannel {
+    /// Average of every channel, i.e. the mid signal for stereo.
+    #[default]
+    Mix,
+    Channel(usize),
+    /// Half the difference between the first two channels.
+    Side,
+}
+
+impl VisChannel {
+    /// The selection after this one, for a source with `channels` channels.
+    pub fn next(self, channels: usize) -> Self {
+        match self {
+            VisChannel::Mix if channels > 1 => VisChannel::Channel(0),
+            VisChannel::Channel(ch) if ch + 1 < channels => VisChannel::Channel(ch + 1),
+            VisChannel::Channel(_) => VisChannel::Side,
+            _ => VisChannel::Mix,
+        }
+    }
+
+    pub fn pick(self, frame: &[f32]) -> f32 {
+        match self {
+            VisChannel::Mix => frame.iter().sum::<f32>() / frame.len() as f32,
+            VisChannel::Channel(ch) => frame.get(ch).copied().unwrap_or(0.0),
+            VisChannel::Side if frame.len() > 1 => (frame[0] - frame[1]) / 2.0,
+            VisChannel::Side => 0.0,
+        }
+    }
+}
+
+/// One frame of audio as the visuals see it.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VisFrame {
+    /// The part of the signal chosen with [`VisChannel`].
+    pub mono: f32,
+    pub left: f32,
+    /// Same as `left` for mono sources.
+    pub right: f32,
+}
+
+impl VisFrame {
+    pub fn new(frame: &[f32], channel: VisChannel) -> Self {
+        let left = frame.first().copied().unwrap_or(0.0);
+        Self {
+            mono: channel.pick(frame),
+            left,
+            right: frame.get(1).copied().unwrap_or(left),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn remixed(input: &[f32], channels: usize) -> Vec<f32> {
+        let mut output = vec![f32::NAN; channels];
+        remix(input, &mut output);
+        output
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} instead of {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn same_layout_is_copied() {
+        assert_eq!(remixed(&[0.1, -0.2], 2), [0.1, -0.2]);
+    }
+
+    #[test]
+    fn mono_goes_to_the_front_pair() {
+        assert_eq!(remixed(&[0.5], 2), [0.5, 0.5]);
+        assert_eq!(remixed(&[0.5], 6), [0.5, 0.5, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn stereo_to_mono_averages() {
+        assert_close(&remixed(&[0.5, -0.25], 1), &[0.125]);
+    }
+
+    #[test]
+    fn surround_folds_down_to_stereo() {
+        let norm = 1.0 / (1.0 + 2.0 * FRAC_1_SQRT_2);
+        // each speaker on its own ends up where it belongs, the LFE nowhere
+        let speaker = |ch: usize| {
+            let mut frame = [0.0; 6];
+            frame[ch] = 1.0;
+            remixed(&frame, 2)
+        };
+        assert_close(&speaker(0), &[norm, 0.0]);
+        assert_close(&speaker(1), &[0.0, norm]);
+        assert_close(&speaker(2), &[FRAC_1_SQRT_2 * norm; 2]);
+        assert_close(&speaker(3), &[0.0, 0.0]);
+        assert_close(&speaker(4), &[FRAC_1_SQRT_2 * norm, 0.0]);
+        assert_close(&speaker(5), &[0.0, FRAC_1_SQRT_2 * norm]);
+        // and all of them at full scale don't clip
+        assert_close(&remixed(&[1.0; 6], 2), &[1.0, 1.0]);
+    }
+
+    #[test]
+    fn other_layouts_pad_or_fold() {
+        assert_eq!(remixed(&[0.1, 0.2], 4), [0.1, 0.2, 0.0, 0.0]);
+        assert_close(&remixed(&[0.2, 0.4, 0.6, 0.8], 2), &[0.4, 0.6]);
+        assert_close(&remixed(&[0.3, 0.6, 0.9], 2), &[0.6, 0.3]);
+    }
+
+    #[test]
+    fn vis_channel_picks_and_cycles() {
+        let frame = [0.5, -0.5];
+        assert_eq!(VisChannel::Mix.pick(&frame), 0.0);
+        assert_eq!(VisChannel::Channel(1).pick(&frame), -0.5);
+        assert_eq!(VisChannel::Channel(5).pick(&frame), 0.0);
+        assert_eq!(VisChannel::Side.pick(&frame), 0.5);
+        assert_eq!(VisChannel::Side.pick(&[0.5]), 0.0);
+
+        let mut channel = VisChannel::Mix;
+        let mut seen = Vec::new();
+        for _ in 0..4 {
+            channel = channel.next(2);
+            seen.push(channel);
+        }
+        assert_eq!(seen, [VisChannel::Channel(0), VisChannel::Channel(1), VisChannel::Side, VisChannel::Mix]);
+        assert_eq!(VisChannel::Mix.next(1), VisChannel::Mix);
+    }
+}
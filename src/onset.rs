@@ -0,0 +1,241 @@
+//! Finds onsets, the starts of notes and drum hits, in a stream of samples.
+
+use crate::spectrum::Spectrum;
+use std::collections::VecDeque;
+
+/// Samples analyzed at a time.
+const WINDOW: usize = 1024;
+/// Samples between the starts of consecutive windows.
+const HOP: usize = 512;
+/// How much stronger than the recent average a change has to be.
+const SENSITIVITY: f32 = 1.5;
+/// Change that always goes unnoticed, so noise in near silence doesn't count.
+const MIN_FLUX: f32 = 0.01;
+/// Seconds of history the average is taken over.
+const HISTORY_SECONDS: f32 = 1.0;
+/// Shortest time between two onsets, in seconds.
+const MIN_GAP_SECONDS: f32 = 0.1;
+
+/// A sudden rise in loudness somewhere in the spectrum.
+#[derive(Debug, Clone, Copy)]
+pub struct Onset {
+    /// When it happened, in seconds since the first sample.
+    pub time: f64,
+    /// How far it stood out, from 0 for barely to 1.
+    pub strength: f32,
+}
+
+/// Spectral flux onset detector: compares the spectrum of each window with
+/// the one before and reports peaks in how much it grew.
+pub struct OnsetDetector {
+    sample_rate: u32,
+    spectrum: Spectrum,
+    samples: Vec<f32>,
+    /// Log magnitudes of the previous window.
+    previous: Vec<f32>,
+    /// Flux of the recent windows, oldest first.
+    history: VecDeque<f32>,
+    history_len: usize,
+    /// Flux, threshold and position of the last window, which is an onset
+    /// if the flux turns out to be a peak.
+    peak: (f32, f32, u64),
+    /// Flux of the window before that.
+    before: f32,
+    /// Position of the first of `samples` in the stream.
+    position: u64,
+    /// Whether the next window is the first since a reset, so there is
+    /// nothing to compare it with.
+    settling: bool,
+    /// Position of the window the last onset was found in.
+    last_onset: Option<u64>,
+    /// Shortest distance between two onsets, in samples.
+    min_gap: u64,
+}
+
+impl OnsetDetector {
+    pub fn new(sample_rate: u32) -> Self {
+        let hops_per_second = sample_rate as f32 / HOP as f32;
+        let history_len = ((HISTORY_SECONDS * hops_per_second) as usize).max(1);
+        Self {
+            sample_rate,
+            spectrum: Spectrum::new(WINDOW),
+            samples: Vec::with_capacity(WINDOW),
+            previous: vec![0.0; WINDOW / 2],
+            history: VecDeque::with_capacity(history_len),
+            history_len,
+            peak: (0.0, f32::INFINITY, 0),
+            before: 0.0,
+            position: 0,
+            settling: false,
+            last_onset: None,
+            min_gap: (MIN_GAP_SECONDS * sample_rate as f32).ceil() as u64,
+        }
+    }
+
+    /// Forgets the music heard so far, for when it jumps somewhere else, so
+    /// the jump itself isn't taken for an onset. Onset times carry on
+    /// counting from where they were.
+    pub fn reset(&mut self) {
+        self.position += self.samples.len() as u64;
+        self.samples.clear();
+        self.history.clear();
+        self.peak = (0.0, f32::INFINITY, 0);
+        self.before = 0.0;
+        self.settling = true;
+    }
+
+    /// Adds the next sample, returning the onset it revealed, if any. Onsets
+    /// are found a little after they happen, `time` says when that was.
+    pub fn push(&mut self, sample: f32) -> Option<Onset> {
+        self.samples.push(sample);
+        if self.samples.len() < WINDOW {
+            return None;
+        }
+        let onset = self.hop();
+        self.samples.drain(..HOP);
+        self.position += HOP as u64;
+        onset
+    }
+
+    fn hop(&mut self) -> Option<Onset> {
+        let magnitudes = self.spectrum.compute(&self.samples);
+        let mut flux = 0.0;
+        for (previous, &magnitude) in self.previous.iter_mut().zip(magnitudes) {
+            // compressed, so quiet instruments count too
+            let level = (1.0 + 100.0 * magnitude).ln();
+            flux += (level - *previous).max(0.0);
+            *previous = level;
+        }
+        let flux = flux / self.previous.len() as f32;
+        if self.settling {
+            self.settling = false;
+            return None;
+        }
+
+        let average = self.history.iter().sum::<f32>() / self.history.len().max(1) as f32;
+        let threshold = SENSITIVITY * average + MIN_FLUX;
+        if self.history.len() == self.history_len {
+            self.history.pop_front();
+        }
+        self.history.push_back(flux);
+
+        // the peak was in the previous window
+        let (peak, peak_threshold, peak_position) = self.peak;
+        let is_peak = peak > peak_threshold && peak >= self.before && peak > flux;
+        self.before = peak;
+        self.peak = (flux, threshold, self.position);
+
+        if !is_peak || self.last_onset.is_some_and(|last| peak_position - last < self.min_gap) {
+            return None;
+        }
+        self.last_onset = Some(peak_position);
+        let middle = peak_position + (WINDOW / 2) as u64;
+        Some(Onset {
+            time: middle as f64 / self.sample_rate as f64,
+            strength: ((peak - peak_threshold) / peak_threshold).min(1.0),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 44100;
+
+    /// Quiet noise with a short burst every `interval` seconds, starting at
+    /// `first`.
+    fn clicks(seconds: f32, first: f32, interval: f32) -> Vec<f32> {
+        let rng = fastrand::Rng::with_seed(7);
+        (0..(seconds * RATE as f32) as usize)
+            .map(|i| {
+                let t = i as f32 / RATE as f32;
+                let since = (t - first).rem_euclid(interval);
+                let noise = rng.f32() * 2.0 - 1.0;
+                if t >= first && since < 0.02 {
+                    noise * 0.8
+                } else {
+                    noise * 0.001
+                }
+            })
+            .collect()
+    }
+
+    fn detect(samples: &[f32]) -> Vec<Onset> {
+        let mut detector = OnsetDetector::new(RATE);
+        samples.iter().filter_map(|&s| detector.push(s)).collect()
+    }
+
+    #[test]
+    fn finds_every_click_on_time() {
+        let onsets = detect(&clicks(4.0, 0.5, 0.5));
+        let times: Vec<f64> = onsets.iter().map(|o| o.time).collect();
+        assert_eq!(onsets.len(), 7, "{:?}", times);
+        let tolerance = WINDOW as f64 / RATE as f64;
+        for (n, onset) in onsets.iter().enumerate() {
+            let expected = 0.5 + 0.5 * n as f64;
+            assert!((onset.time - expected).abs() < tolerance, "onset {} at {}", n, onset.time);
+            assert!((0.0..=1.0).contains(&onset.strength), "{}", onset.strength);
+        }
+    }
+
+    #[test]
+    fn silence_and_steady_tones_have_no_onsets() {
+        assert!(detect(&vec![0.0; RATE as usize * 2]).is_empty());
+        let tone: Vec<f32> = (0..RATE * 2)
+            .map(|i| (i as f32 * 440.0 * std::f32::consts::TAU / RATE as f32).sin() * 0.5)
+            .collect();
+        // the tone starting is the only change
+        assert!(detect(&tone).len() <= 1);
+    }
+
+    #[test]
+    fn onsets_are_spaced_out() {
+        let onsets = detect(&clicks(2.0, 0.1, 0.03));
+        for pair in onsets.windows(2) {
+            assert!(pair[1].time - pair[0].time >= MIN_GAP_SECONDS as f64 - 1e-6);
+        }
+    }
+
+    /// Quiet noise for `seconds`, then a steady loud tone.
+    fn cut(seconds: f32) -> (Vec<f32>, Vec<f32>) {
+        let before = clicks(seconds, seconds, 1.0);
+        let after = (0..RATE)
+            .map(|i| (i as f32 * 1000.0 * std::f32::consts::TAU / RATE as f32).sin() * 0.7)
+            .collect();
+        (before, after)
+    }
+
+    #[test]
+    fn hard_cut_after_a_reset_is_not_an_onset() {
+        // 100 samples into a hop, so the cut falls inside a window
+        let (before, after) = cut(1.0 + 100.0 / RATE as f32);
+        let mut detector = OnsetDetector::new(RATE);
+        assert!(before.iter().all(|&s| detector.push(s).is_none()));
+        assert!(after.iter().any(|&s| detector.push(s).is_some()), "the cut should be found without a reset");
+
+        let mut detector = OnsetDetector::new(RATE);
+        assert!(before.iter().all(|&s| detector.push(s).is_none()));
+        detector.reset();
+        let onsets: Vec<f64> = after.iter().filter_map(|&s| detector.push(s)).map(|o| o.time).collect();
+        assert!(onsets.is_empty(), "{:?}", onsets);
+    }
+
+    #[test]
+    fn times_carry_on_after_a_reset() {
+        let samples = clicks(4.0, 0.5, 0.5);
+        // somewhere between two clicks and between two hops
+        let split = (1.2 * RATE as f32) as usize + 100;
+        let mut detector = OnsetDetector::new(RATE);
+        let mut onsets: Vec<Onset> = samples[..split].iter().filter_map(|&s| detector.push(s)).collect();
+        detector.reset();
+        onsets.extend(samples[split..].iter().filter_map(|&s| detector.push(s)));
+        let times: Vec<f64> = onsets.iter().map(|o| o.time).collect();
+        assert_eq!(onsets.len(), 7, "{:?}", times);
+        let tolerance = WINDOW as f64 / RATE as f64;
+        for (n, onset) in onsets.iter().enumerate() {
+            let expected = 0.5 + 0.5 * n as f64;
+            assert!((onset.time - expected).abs() < tolerance, "onset {} at {}", n, onset.time);
+        }
+    }
+}
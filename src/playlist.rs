@@ -0,0 +1,196 @@
+use std::path::{Path, PathBuf};
+
+/// What happens when a track finishes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Repeat {
+    /// Stop after the last track.
+    Off,
+    One,
+    #[default]
+    All,
+}
+
+impl Repeat {
+    pub fn next(self) -> Self {
+        match self {
+            Repeat::Off => Repeat::One,
+            Repeat::One => Repeat::All,
+            Repeat::All => Repeat::Off,
+        }
+    }
+}
+
+/// Every wav file in a directory, played in name order or shuffled.
+pub struct Playlist {
+    tracks: Vec<PathBuf>,
+    /// Indices into `tracks` in the order they are played.
+    order: Vec<usize>,
+    pos: usize,
+    shuffle: bool,
+    repeat: Repeat,
+}
+
+impl Playlist {
+    /// A playlist of the wav files in `path` if it is a directory, or of
+    /// just `path` otherwise.
+    pub fn from_path(path: &Path) -> anyhow::Result<Self> {
+        if path.is_dir() {
+            Self::from_dir(path)
+        } else {
+            anyhow::ensure!(path.is_file(), "{} does not exist", path.display());
+            Ok(Self::new(vec![path.to_path_buf()]))
+        }
+    }
+
+    fn from_dir(dir: &Path) -> anyhow::Result<Self> {
+        let mut tracks: Vec<PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(Result::ok)
+            .map(|x| x.path())
+            .filter(|x| x.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wav")))
+            .collect();
+        anyhow::ensure!(!tracks.is_empty(), "no wav file in {}", dir.display());
+        tracks.sort();
+        Ok(Self::new(tracks))
+    }
+
+    fn new(tracks: Vec<PathBuf>) -> Self {
+        Self {
+            order: (0..tracks.len()).collect(),
+            tracks,
+            pos: 0,
+            shuffle: false,
+            repeat: Repeat::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn current(&self) -> &Path {
+        &self.tracks[self.order[self.pos]]
+    }
+
+    /// Skips to the next track, wrapping around at the end.
+    pub fn next(&mut self) -> &Path {
+        self.pos = (self.pos + 1) % self.order.len();
+        self.current()
+    }
+
+    /// Goes back to the previous track, wrapping around at the start.
+    pub fn previous(&mut self) -> &Path {
+        self.pos = (self.pos + self.order.len() - 1) % self.order.len();
+        self.current()
+    }
+
+    /// The track to play once the current one has finished, if any.
+    pub fn advance(&mut self) -> Option<&Path> {
+        match self.repeat {
+            Repeat::One => Some(self.current()),
+            Repeat::All => Some(self.next()),
+            Repeat::Off if self.pos + 1 < self.order.len() => Some(self.next()),
+            Repeat::Off => None,
+        }
+    }
+
+    /// Turns shuffling on or off. The current track keeps playing either way.
+    pub fn toggle_shuffle(&mut self) -> bool {
+        let current = self.order[self.pos];
+        self.shuffle = !self.shuffle;
+        self.order = (0..self.tracks.len()).collect();
+        if self.shuffle {
+            fastrand::shuffle(&mut self.order);
+        }
+        self.pos = self.order.iter().position(|&i| i == current).unwrap_or(0);
+        if self.shuffle {
+            // play the rest of the shuffled list from here
+            self.order.swap(0, self.pos);
+            self.pos = 0;
+        }
+        self.shuffle
+    }
+
+    pub fn cycle_repeat(&mut self) -> Repeat {
+        self.repeat = self.repeat.next();
+        self.repeat
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(len: usize) -> Playlist {
+        Playlist::new((0..len).map(|i| PathBuf::from(format!("{}.wav", i))).collect())
+    }
+
+    fn name(path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn next_and_previous_wrap_around() {
+        let mut list = playlist(3);
+        assert_eq!(name(list.current()), "0.wav");
+        assert_eq!(name(list.previous()), "2.wav");
+        assert_eq!(name(list.next()), "0.wav");
+        assert_eq!(name(list.next()), "1.wav");
+        assert_eq!(name(list.next()), "2.wav");
+        assert_eq!(name(list.next()), "0.wav");
+    }
+
+    #[test]
+    fn repeat_modes() {
+        let mut list = playlist(2);
+        // all, by default
+        assert_eq!(name(list.advance().unwrap()), "1.wav");
+        assert_eq!(name(list.advance().unwrap()), "0.wav");
+
+        assert_eq!(list.cycle_repeat(), Repeat::Off);
+        assert_eq!(name(list.advance().unwrap()), "1.wav");
+        assert!(list.advance().is_none());
+
+        assert_eq!(list.cycle_repeat(), Repeat::One);
+        assert_eq!(name(list.advance().unwrap()), "1.wav");
+        assert_eq!(name(list.advance().unwrap()), "1.wav");
+        assert_eq!(list.cycle_repeat(), Repeat::All);
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once() {
+        fastrand::seed(1);
+        let mut list = playlist(10);
+        list.next();
+        assert!(list.toggle_shuffle());
+        // the current track carries on, then the rest follow in some order
+        assert_eq!(name(list.current()), "1.wav");
+        let mut played: Vec<String> = (0..10).map(|_| name(list.next())).collect();
+        assert_eq!(played.last().unwrap(), "1.wav");
+        played.sort();
+        let mut all: Vec<String> = (0..10).map(|i| format!("{}.wav", i)).collect();
+        all.sort();
+        assert_eq!(played, all);
+
+        assert!(!list.toggle_shuffle());
+        let current = name(list.current());
+        let next = name(list.next());
+        let index = |s: &str| s.trim_end_matches(".wav").parse::<usize>().unwrap();
+        assert_eq!(index(&next), (index(&current) + 1) % 10);
+    }
+
+    #[test]
+    fn directories_list_wav_files_in_name_order() {
+        let dir = std::env::temp_dir().join(format!("visgra_playlist_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for file in ["b.wav", "a.WAV", "c.txt"] {
+            std::fs::write(dir.join(file), b"").unwrap();
+        }
+        let list = Playlist::from_path(&dir);
+        let missing = Playlist::from_path(&dir.join("d.wav"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let list = list.unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.current().file_name().unwrap(), "a.WAV");
+        assert!(missing.is_err());
+    }
+}
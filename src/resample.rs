@@ -0,0 +1,238 @@
+use crate::source::Source;
+
+/// How the resampler interpolates between source frames.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Quality {
+    /// Repeats or drops frames. Cheapest, and audibly harsh.
+    Nearest,
+    /// Straight line between neighbouring frames.
+    #[default]
+    Linear,
+    /// Catmull-Rom spline through the four closest frames.
+    Cubic,
+}
+
+impl Quality {
+    pub fn next(self) -> Self {
+        match self {
+            Quality::Nearest => Quality::Linear,
+            Quality::Linear => Quality::Cubic,
+            Quality::Cubic => Quality::Nearest,
+        }
+    }
+}
+
+/// Converts frames pulled from a [`Source`] to another sample rate.
+pub struct Resampler {
+    quality: Quality,
+    channels: usize,
+    /// Source frames advanced per output frame.
+    ratio: f64,
+    /// Position between `window[1]` and `window[2]`, in 0..1.
+    pos: f64,
+    /// The four source frames around `pos`, interleaved.
+    window: Vec<f32>,
+    primed: bool,
+    /// Frames of padding pulled since the source ran dry.
+    padding: usize,
+}
+
+impl Resampler {
+    pub fn new(quality: Quality, source_rate: u32, target_rate: u32, channels: u16) -> Self {
+        let channels = channels as usize;
+        Self {
+            quality,
+            channels,
+            ratio: source_rate as f64 / target_rate as f64,
+            pos: 0.0,
+            window: vec![0.0; channels * 4],
+            primed: false,
+            padding: 0,
+        }
+    }
+
+    /// Interpolates with `quality` from now on.
+    pub fn set_quality(&mut self, quality: Quality) {
+        self.quality = quality;
+    }
+
+    /// Forgets the frames seen so far, for when the source has jumped.
+    pub fn reset(&mut self) {
+        self.pos = 0.0;
+        self.window.fill(0.0);
+        self.primed = false;
+        self.padding = 0;
+    }
+
+    /// Shifts the window one frame along, pulling a new frame from `source`.
+    fn advance<S: Source + ?Sized>(&mut self, source: &mut S) {
+        self.window.copy_within(self.channels.., 0);
+        let newest = self.channels * 3;
+        for ch in 0..self.channels {
+            self.window[newest + ch] = match source.next_sample() {
+                Some(s) => s,
+                None => {
+                    if ch == 0 {
+                        self.padding += 1;
+                    }
+                    0.0
+                }
+            };
+        }
+    }
+
+    /// Writes the next output frame into `out`, which holds one sample per
+    /// source channel. Returns false once the source has run dry and every
+    /// frame it gave has been played.
+    pub fn next_frame<S: Source + ?Sized>(&mut self, source: &mut S, out: &mut [f32]) -> bool {
+        if !self.primed {
+            // window[1] is the first frame of the source
+            for _ in 0..3 {
+                self.advance(source);
+            }
+            self.primed = true;
+        }
+        // window[1] is padding once three frames of it have been pulled
+        if self.padding >= 3 {
+            return false;
+        }
+
+        let t = self.pos as f32;
+        for (ch, sample) in out.iter_mut().enumerate().take(self.channels) {
+            let p = |i: usize| self.window[i * self.channels + ch];
+            *sample = match self.quality {
+                Quality::Nearest => {
+                    if t < 0.5 {
+                        p(1)
+                    } else {
+                        p(2)
+                    }
+                }
+                Quality::Linear => p(1) + (p(2) - p(1)) * t,
+                Quality::Cubic => {
+                    let (p0, p1, p2, p3) = (p(0), p(1), p(2), p(3));
+                    p1 + 0.5
+                        * t
+                        * (p2 - p0
+                            + t * (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3
+                                + t * (3.0 * (p1 - p2) + p3 - p0)))
+                }
+            };
+        }
+
+        self.pos += self.ratio;
+        while self.pos >= 1.0 {
+            self.pos -= 1.0;
+            self.advance(source);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::TAU;
+
+    /// Plays the given interleaved samples once.
+    struct Samples {
+        samples: std::vec::IntoIter<f32>,
+        channels: u16,
+        sample_rate: u32,
+    }
+
+    impl Source for Samples {
+        fn next_sample(&mut self) -> Option<f32> {
+            self.samples.next()
+        }
+
+        fn channels(&self) -> u16 {
+            self.channels
+        }
+
+        fn sample_rate(&self) -> u32 {
+            self.sample_rate
+        }
+    }
+
+    const QUALITIES: [Quality; 3] = [Quality::Nearest, Quality::Linear, Quality::Cubic];
+
+    /// Resamples mono `samples` from `from` to `to` Hz.
+    fn resample(quality: Quality, samples: Vec<f32>, from: u32, to: u32) -> Vec<f32> {
+        let mut source = Samples { samples: samples.into_iter(), channels: 1, sample_rate: from };
+        let mut resampler = Resampler::new(quality, from, to, 1);
+        let mut out = Vec::new();
+        let mut frame = [0.0];
+        while resampler.next_frame(&mut source, &mut frame) {
+            out.push(frame[0]);
+        }
+        out
+    }
+
+    fn sine(frequency: f32, sample_rate: u32, len: usize) -> Vec<f32> {
+        (0..len).map(|i| (TAU * frequency * i as f32 / sample_rate as f32).sin()).collect()
+    }
+
+    #[test]
+    fn output_length_follows_the_rate() {
+        for quality in QUALITIES {
+            for (from, to) in [(44100, 48000), (48000, 44100), (22050, 44100), (44100, 44100)] {
+                let out = resample(quality, vec![0.0; 44100], from, to);
+                let expected = 44100 * to as usize / from as usize;
+                assert!(out.len().abs_diff(expected) <= 1, "{:?} {}->{}: {}", quality, from, to, out.len());
+            }
+        }
+    }
+
+    #[test]
+    fn same_rate_passes_samples_through() {
+        let input = sine(1000.0, 44100, 1000);
+        for quality in QUALITIES {
+            assert_eq!(resample(quality, input.clone(), 44100, 44100), input, "{:?}", quality);
+        }
+    }
+
+    #[test]
+    fn stereo_channels_stay_apart() {
+        let input = [0.5, -0.5].repeat(100);
+        let mut source = Samples { samples: input.into_iter(), channels: 2, sample_rate: 32000 };
+        let mut resampler = Resampler::new(Quality::Linear, 32000, 48000, 2);
+        let mut frame = [0.0; 2];
+        // up to the last frame, which fades into the silence after the end
+        for _ in 0..140 {
+            assert!(resampler.next_frame(&mut source, &mut frame));
+            assert_eq!(frame, [0.5, -0.5]);
+        }
+    }
+
+    #[test]
+    fn dc_stays_flat() {
+        for quality in QUALITIES {
+            let out = resample(quality, vec![0.5; 4410], 44100, 48000);
+            // cubic looks a frame back and ahead, into the silence before the
+            // start and after the end
+            for &sample in &out[3..out.len() - 3] {
+                assert!((sample - 0.5).abs() < 1e-6, "{:?}: {}", quality, sample);
+            }
+        }
+    }
+
+    #[test]
+    fn sine_passes_without_steps() {
+        let (frequency, from, to) = (1000.0, 44100, 48000);
+        for (quality, tolerance) in [(Quality::Nearest, 0.08), (Quality::Linear, 0.003), (Quality::Cubic, 0.0005)] {
+            let out = resample(quality, sine(frequency, from, 4410), from, to);
+            let ideal = sine(frequency, to, out.len());
+            let error = out[2..out.len() - 2]
+                .iter()
+                .zip(&ideal[2..])
+                .fold(0.0f32, |e, (a, b)| e.max((a - b).abs()));
+            assert!(error < tolerance, "{:?} is off by {}", quality, error);
+            // never jumps further than the sine moves between source frames
+            let max_step = TAU * frequency / from as f32 * 1.01;
+            for pair in out[2..out.len() - 2].windows(2) {
+                assert!((pair[1] - pair[0]).abs() <= max_step, "{:?}: {:?}", quality, pair);
+            }
+        }
+    }
+}
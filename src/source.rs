@@ -0,0 +1,457 @@
+use ringbuf::{Consumer, HeapRb};
+use std::{f32::consts::TAU, fs::File, io::BufReader, path::Path, sync::Arc};
+
+/// Something the audio thread can pull interleaved samples from.
+pub trait Source: Send {
+    /// The next interleaved sample, or `None` once the source has run dry.
+    fn next_sample(&mut self) -> Option<f32>;
+    fn channels(&self) -> u16;
+    fn sample_rate(&self) -> u32;
+
+    /// Frame that will be read next, for sources that can seek.
+    fn position(&self) -> Option<u64> {
+        None
+    }
+
+    /// Jumps to `frame`, clamped to the length of the source. Returns false
+    /// if the source can't seek.
+    fn seek(&mut self, _frame: u64) -> bool {
+        false
+    }
+
+    /// What went wrong reading the source, if anything did. It is handed
+    /// out once, for whoever closes the source to report, since the output
+    /// callback that plays it can't.
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        None
+    }
+}
+
+/// How the samples in a wav file are stored.
+enum Format {
+    Float,
+    /// Integer PCM, along with the factor that normalizes it to -1..1.
+    Int(f32),
+}
+
+/// Samples decoded from a wav file, normalized to -1..1 whatever the format.
+pub struct WavSource {
+    reader: hound::WavReader<BufReader<File>>,
+    format: Format,
+    spec: hound::WavSpec,
+    /// Samples read since the start of the file.
+    read: u64,
+    /// The last thing that went wrong reading or seeking.
+    error: Option<hound::Error>,
+}
+
+impl WavSource {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let format = match (spec.sample_format, spec.bits_per_sample) {
+            (hound::SampleFormat::Float, 32) => Format::Float,
+            (hound::SampleFormat::Int, bits @ 8..=32) => Format::Int(1.0 / (1u64 << (bits - 1)) as f32),
+            (format, bits) => anyhow::bail!(
+                "unsupported wav format in {}: {}-bit {:?}",
+                path.display(),
+                bits,
+                format
+            ),
+        };
+        Ok(Self {
+            reader,
+            format,
+            spec,
+            read: 0,
+            error: None,
+        })
+    }
+}
+
+impl Source for WavSource {
+    fn next_sample(&mut self) -> Option<f32> {
+        let sample = match self.format {
+            Format::Float => self.reader.samples::<f32>().next()?,
+            Format::Int(scale) => self.reader.samples::<i32>().next()?.map(|s| s as f32 * scale),
+        };
+        match sample {
+            Ok(s) => {
+                self.read += 1;
+                Some(s)
+            }
+            Err(e) => {
+                // a broken file ends the song instead of playing garbage
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        self.spec.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.spec.sample_rate
+    }
+
+    fn position(&self) -> Option<u64> {
+        Some(self.read / self.spec.channels as u64)
+    }
+
+    fn seek(&mut self, frame: u64) -> bool {
+        let frame = frame.min(self.reader.duration() as u64);
+        match self.reader.seek(frame as u32) {
+            Ok(()) => {
+                self.read = frame * self.spec.channels as u64;
+                true
+            }
+            Err(e) => {
+                self.error = Some(hound::Error::IoError(e));
+                false
+            }
+        }
+    }
+
+    fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take().map(anyhow::Error::from)
+    }
+}
+
+/// Samples recorded by the input stream, handed over through a ring buffer.
+/// Never runs dry; plays silence while the input is falling behind.
+pub struct MicSource {
+    cons: Consumer<f32, Arc<HeapRb<f32>>>,
+    channels: u16,
+    sample_rate: u32,
+    underflows: u64,
+}
+
+impl MicSource {
+    pub fn new(cons: Consumer<f32, Arc<HeapRb<f32>>>, channels: u16, sample_rate: u32) -> Self {
+        Self {
+            cons,
+            channels,
+            sample_rate,
+            underflows: 0,
+        }
+    }
+
+    /// Samples that were played as silence because they hadn't been
+    /// recorded yet.
+    pub fn underflows(&self) -> u64 {
+        self.underflows
+    }
+}
+
+impl Source for MicSource {
+    fn next_sample(&mut self) -> Option<f32> {
+        Some(self.cons.pop().unwrap_or_else(|| {
+            self.underflows += 1;
+            0.0
+        }))
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// What plays when the microphone doesn't.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SourceKind {
+    /// The wav files given on the command line.
+    #[default]
+    File,
+    /// A 440 Hz sine wave.
+    Tone,
+    /// A sine wave gliding up from 20 Hz to 20 kHz, over and over.
+    Sweep,
+    Silence,
+}
+
+/// Seconds the sweep takes from its lowest to its highest frequency.
+const SWEEP_SECONDS: f32 = 10.0;
+
+impl SourceKind {
+    /// A mono source of this kind at `sample_rate`, or `None` for files,
+    /// which have to be opened.
+    pub fn synthetic(self, sample_rate: u32) -> Option<Box<dyn Source>> {
+        match self {
+            SourceKind::File => None,
+            SourceKind::Tone => Some(Box::new(Tone::new(440.0, 0.5, sample_rate))),
+            SourceKind::Sweep => {
+                let mut phase = 0.0f32;
+                Some(Box::new(Generator::new(sample_rate, move |t| {
+                    // the same number of seconds for every octave
+                    let frequency = 20.0 * 1000f32.powf((t / SWEEP_SECONDS).fract());
+                    let sample = (phase * TAU).sin() * 0.5;
+                    phase = (phase + frequency / sample_rate as f32).fract();
+                    sample
+                })))
+            }
+            SourceKind::Silence => Some(Box::new(Silence::new(1, sample_rate))),
+        }
+    }
+}
+
+/// Mono signal computed from the time in seconds since it started.
+pub struct Generator<F> {
+    f: F,
+    sample_rate: u32,
+    frame: u64,
+}
+
+impl<F: FnMut(f32) -> f32 + Send> Generator<F> {
+    pub fn new(sample_rate: u32, f: F) -> Self {
+        Self {
+            f,
+            sample_rate,
+            frame: 0,
+        }
+    }
+}
+
+impl<F: FnMut(f32) -> f32 + Send> Source for Generator<F> {
+    fn next_sample(&mut self) -> Option<f32> {
+        let t = self.frame as f32 / self.sample_rate as f32;
+        self.frame += 1;
+        Some((self.f)(t))
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// Mono sine wave, for checking the output without a wav file.
+pub struct Tone {
+    step: f32,
+    phase: f32,
+    amplitude: f32,
+    sample_rate: u32,
+}
+
+impl Tone {
+    pub fn new(frequency: f32, amplitude: f32, sample_rate: u32) -> Self {
+        Self {
+            step: frequency / sample_rate as f32,
+            phase: 0.0,
+            amplitude,
+            sample_rate,
+        }
+    }
+}
+
+impl Source for Tone {
+    fn next_sample(&mut self) -> Option<f32> {
+        let sample = (self.phase * TAU).sin() * self.amplitude;
+        // keep the phase small so it doesn't lose precision over time
+        self.phase = (self.phase + self.step).fract();
+        Some(sample)
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// Plays nothing, forever.
+pub struct Silence {
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl Silence {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        Self {
+            channels,
+            sample_rate,
+        }
+    }
+}
+
+impl Source for Silence {
+    fn next_sample(&mut self) -> Option<f32> {
+        Some(0.0)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(source: &mut dyn Source, count: usize) -> Vec<f32> {
+        (0..count).map_while(|_| source.next_sample()).collect()
+    }
+
+    /// Writes `samples` to a temporary wav file called `name`.
+    fn write_wav<S: hound::Sample + Copy>(name: &str, spec: hound::WavSpec, samples: &[S]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("visgra_{}_{}.wav", name, std::process::id()));
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    }
+
+    fn int_spec(bits: u16) -> hound::WavSpec {
+        hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: bits,
+            sample_format: hound::SampleFormat::Int,
+        }
+    }
+
+    /// Writes `samples` as a mono wav file with `bits` bit integer samples
+    /// and reads it back.
+    fn round_trip(bits: u16, samples: &[i32]) -> Vec<f32> {
+        let path = write_wav(&format!("int{}", bits), int_spec(bits), samples);
+        let mut source = WavSource::open(&path).unwrap();
+        let read = take(&mut source, samples.len() + 1);
+        std::fs::remove_file(&path).unwrap();
+        read
+    }
+
+    #[test]
+    fn integer_wavs_are_scaled_to_full_scale() {
+        for bits in [8, 16, 24, 32] {
+            let max = ((1i64 << (bits - 1)) - 1) as i32;
+            let read = round_trip(bits, &[i32::MIN >> (32 - bits), 0, max, max / 2]);
+            assert_eq!(read.len(), 4, "{}-bit", bits);
+            assert_eq!(read[0], -1.0, "{}-bit", bits);
+            assert_eq!(read[1], 0.0, "{}-bit", bits);
+            assert!((read[2] - 1.0).abs() <= 1.0 / 128.0, "{}-bit: {}", bits, read[2]);
+            assert!((read[3] - 0.5).abs() <= 1.0 / 128.0, "{}-bit: {}", bits, read[3]);
+        }
+    }
+
+    #[test]
+    fn unsigned_8_bit_silence_is_zero() {
+        // 8-bit wavs are stored offset by 128, so the middle byte is silence
+        let path = write_wav("u8", int_spec(8), &[0i8, -128, 127]);
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes[bytes.len() - 3..], [128, 0, 255]);
+        let read = take(&mut WavSource::open(&path).unwrap(), 4);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, [0.0, -1.0, 127.0 / 128.0]);
+    }
+
+    #[test]
+    fn float_wavs_are_read_as_is() {
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let path = write_wav("f32", spec, &[0.25f32, -0.5, 1.0, -1.0]);
+        let mut source = WavSource::open(&path).unwrap();
+        assert_eq!((source.channels(), source.sample_rate()), (2, 48000));
+        let read = take(&mut source, 5);
+        assert_eq!(source.position(), Some(2));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read, [0.25, -0.5, 1.0, -1.0]);
+    }
+
+    #[test]
+    fn tone_has_its_frequency_and_amplitude() {
+        let samples = take(&mut Tone::new(100.0, 0.5, 8000), 8000);
+        let crossings = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((99..=100).contains(&crossings), "{}", crossings);
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - 0.5).abs() < 1e-3, "{}", peak);
+    }
+
+    #[test]
+    fn generator_is_given_the_time() {
+        let mut generator = Generator::new(4, |t| t * 10.0);
+        assert_eq!((generator.channels(), generator.sample_rate()), (1, 4));
+        assert_eq!(take(&mut generator, 6), [0.0, 2.5, 5.0, 7.5, 10.0, 12.5]);
+        assert_eq!(generator.position(), None);
+    }
+
+    #[test]
+    fn synthetic_sources_play_forever() {
+        assert!(SourceKind::File.synthetic(8000).is_none());
+        for kind in [SourceKind::Tone, SourceKind::Sweep, SourceKind::Silence] {
+            let mut source = kind.synthetic(8000).unwrap();
+            assert_eq!((source.channels(), source.sample_rate()), (1, 8000));
+            let samples = take(source.as_mut(), 8000);
+            assert_eq!(samples.len(), 8000);
+            let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            let expected = if kind == SourceKind::Silence { 0.0 } else { 0.5 };
+            assert!((peak - expected).abs() < 1e-3, "{:?} peaks at {}", kind, peak);
+        }
+    }
+
+    #[test]
+    fn sweep_rises_through_the_octaves() {
+        let mut sweep = SourceKind::Sweep.synthetic(8000).unwrap();
+        // rising zero crossings in the first second and in the fourth,
+        // which the frequency integrated over them says are 29 and 229
+        let crossings = |samples: &[f32]| samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        let first = crossings(&take(sweep.as_mut(), 8000));
+        take(sweep.as_mut(), 8000 * 2);
+        let fourth = crossings(&take(sweep.as_mut(), 8000));
+        assert!((26..=32).contains(&first), "{}", first);
+        assert!((220..=238).contains(&fourth), "{}", fourth);
+    }
+
+    #[test]
+    fn silence_never_runs_dry() {
+        let mut silence = Silence::new(2, 48000);
+        assert_eq!((silence.channels(), silence.sample_rate()), (2, 48000));
+        assert!(take(&mut silence, 1000).iter().all(|&s| s == 0.0));
+        assert_eq!(silence.position(), None);
+        assert!(!silence.seek(10));
+    }
+
+    #[test]
+    fn mic_plays_silence_while_the_input_falls_behind() {
+        let (mut prod, cons) = HeapRb::new(8).split();
+        let mut mic = MicSource::new(cons, 1, 44100);
+        prod.push_slice(&[0.1, 0.2, 0.3]);
+        assert_eq!(take(&mut mic, 5), [0.1, 0.2, 0.3, 0.0, 0.0]);
+        assert_eq!(mic.underflows(), 2);
+        prod.push_slice(&[0.4]);
+        assert_eq!(mic.next_sample(), Some(0.4));
+        assert_eq!(mic.underflows(), 2);
+    }
+
+    #[test]
+    fn truncated_wav_ends_early_and_keeps_the_error() {
+        let path = write_wav("truncated", int_spec(16), &[1000i16; 10]);
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        // cut the last sample in half
+        file.set_len(file.metadata().unwrap().len() - 1).unwrap();
+        let mut source = WavSource::open(&path).unwrap();
+        assert_eq!(take(&mut source, 11).len(), 9);
+        assert!(source.take_error().is_some());
+        assert!(source.take_error().is_none());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
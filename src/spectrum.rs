@@ -0,0 +1,121 @@
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{f32::consts::TAU, sync::Arc};
+
+/// Magnitude spectrum of the most recent samples.
+pub struct Spectrum {
+    fft: Arc<dyn Fft<f32>>,
+    /// Hann window, to keep loud bins from smearing over the whole spectrum.
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    /// Working space for the FFT, so it doesn't allocate its own each time.
+    scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+}
+
+impl Spectrum {
+    /// Analyzes blocks of `size` samples, giving `size / 2` frequency bins.
+    pub fn new(size: usize) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(size);
+        Self {
+            scratch: vec![Complex::default(); fft.get_inplace_scratch_len()],
+            fft,
+            window: (0..size)
+                .map(|i| 0.5 - 0.5 * (TAU * i as f32 / size as f32).cos())
+                .collect(),
+            buffer: vec![Complex::default(); size],
+            magnitudes: vec![0.0; size / 2],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Computes the windowed spectrum of the last `size` values of `samples`,
+    /// padding with silence if there are fewer. Magnitudes are scaled so that
+    /// a full scale sine wave peaks at 1.
+    pub fn compute(&mut self, samples: &[f32]) -> &[f32] {
+        let size = self.size();
+        let recent = &samples[samples.len().saturating_sub(size)..];
+        self.buffer.fill(Complex::default());
+        for ((slot, &sample), w) in self.buffer.iter_mut().zip(recent).zip(&self.window) {
+            slot.re = sample * w;
+        }
+        self.fft.process_with_scratch(&mut self.buffer, &mut self.scratch);
+        // the window halves the amplitude on average
+        let scale = 4.0 / size as f32;
+        for (magnitude, bin) in self.magnitudes.iter_mut().zip(&self.buffer) {
+            *magnitude = bin.norm() * scale;
+        }
+        &self.magnitudes
+    }
+}
+
+/// Spreads `magnitudes` over `bands` on a logarithmic frequency scale, so
+/// every octave gets the same width. Each band gets its loudest bin.
+pub fn log_bands(magnitudes: &[f32], bands: &mut [f32]) {
+    let bins = magnitudes.len() as f32;
+    let n = bands.len() as f32;
+    for (i, band) in bands.iter_mut().enumerate() {
+        // skip the DC bin, it says nothing about the sound. Edges are
+        // rounded so octaves don't lose their last bin to float error.
+        let lo = bins.powf(i as f32 / n).round() as usize;
+        let hi = (bins.powf((i + 1) as f32 / n).round() as usize).max(lo + 1);
+        *band = magnitudes[lo.min(magnitudes.len() - 1)..hi.min(magnitudes.len())]
+            .iter()
+            .fold(0.0, |a: f32, &b| a.max(b));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bands lit up by a single loud bin.
+    fn lit(bins: usize, bin: usize, bands: usize) -> Vec<usize> {
+        let mut magnitudes = vec![0.0; bins];
+        magnitudes[bin] = 1.0;
+        let mut out = vec![0.0; bands];
+        log_bands(&magnitudes, &mut out);
+        (0..bands).filter(|&i| out[i] > 0.0).collect()
+    }
+
+    #[test]
+    fn every_octave_gets_a_band() {
+        // 1024 bins over 10 bands makes band i cover bins 2^i up to 2^(i+1)
+        for band in 0..10 {
+            let (lo, hi) = (1 << band, (2 << band) - 1);
+            assert_eq!(lit(1024, lo, 10), [band], "bin {}", lo);
+            assert_eq!(lit(1024, hi, 10), [band], "bin {}", hi);
+        }
+    }
+
+    #[test]
+    fn dc_is_skipped() {
+        assert!(lit(1024, 0, 10).is_empty());
+    }
+
+    #[test]
+    fn every_bin_lands_in_some_band() {
+        for bands in [7, 32, 100] {
+            for bin in 1..256 {
+                assert!(!lit(256, bin, bands).is_empty(), "bin {} with {} bands", bin, bands);
+            }
+        }
+    }
+
+    #[test]
+    fn full_scale_sine_peaks_at_one() {
+        let mut spectrum = Spectrum::new(1024);
+        // exactly on bin 64
+        let samples: Vec<f32> = (0..1024).map(|i| (TAU * 64.0 * i as f32 / 1024.0).sin()).collect();
+        let magnitudes = spectrum.compute(&samples);
+        let (peak, &loudest) = magnitudes
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+        assert_eq!(peak, 64);
+        assert!((loudest - 1.0).abs() < 0.01, "{}", loudest);
+    }
+}
@@ -0,0 +1,190 @@
+//! Estimates the tempo of the music and where in the beat it is from its
+//! onsets.
+
+use crate::onset::Onset;
+use std::collections::VecDeque;
+use std::f64::consts::TAU;
+
+/// Seconds of onsets the estimate is based on.
+const WINDOW_SECONDS: f64 = 8.0;
+/// Longest time between two onsets that is compared, in seconds.
+const MAX_INTERVAL: f64 = 2.0;
+/// Onsets needed before there is an estimate.
+const MIN_ONSETS: usize = 4;
+const MIN_BPM: f32 = 60.0;
+const MAX_BPM: f32 = 200.0;
+/// Difference between candidate tempos tried, in beats per minute.
+const BPM_STEP: f32 = 0.5;
+/// How far off an interval may be from a whole number of beats and still
+/// count, in seconds.
+const TOLERANCE: f64 = 0.02;
+
+/// A tempo and where its beats fall.
+#[derive(Debug, Clone, Copy)]
+pub struct Tempo {
+    pub bpm: f32,
+    /// Time of one of the beats, in seconds.
+    beat: f64,
+}
+
+impl Tempo {
+    /// Seconds between two beats.
+    pub fn period(&self) -> f64 {
+        60.0 / self.bpm as f64
+    }
+
+    /// How far into the current beat `time` is, from 0 on the beat to just
+    /// below 1 right before the next one.
+    pub fn phase(&self, time: f64) -> f32 {
+        ((time - self.beat) / self.period()).rem_euclid(1.0) as f32
+    }
+}
+
+/// Keeps a tempo estimate up to date from onsets as they are found.
+#[derive(Default)]
+pub struct TempoTracker {
+    /// Onsets within the window, oldest first.
+    onsets: VecDeque<Onset>,
+    tempo: Option<Tempo>,
+}
+
+impl TempoTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current estimate, if there have been enough onsets recently.
+    pub fn tempo(&self) -> Option<Tempo> {
+        self.tempo
+    }
+
+    /// Adds the next onset and updates the estimate.
+    pub fn push(&mut self, onset: Onset) {
+        while self.onsets.front().is_some_and(|first| onset.time - first.time > WINDOW_SECONDS) {
+            self.onsets.pop_front();
+        }
+        self.onsets.push_back(onset);
+        self.tempo = self.estimate();
+    }
+
+    fn estimate(&self) -> Option<Tempo> {
+        if self.onsets.len() < MIN_ONSETS {
+            return None;
+        }
+        let candidates = ((MAX_BPM - MIN_BPM) / BPM_STEP) as usize + 1;
+        let bpm = (0..candidates)
+            .map(|i| MIN_BPM + i as f32 * BPM_STEP)
+            .map(|bpm| (bpm, self.score(60.0 / bpm as f64)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .filter(|&(_, score)| score > 0.0)?
+            .0;
+
+        // the beats fall where the onsets are on average, going round the beat
+        let period = 60.0 / bpm as f64;
+        let (sin, cos) = self.onsets.iter().fold((0.0, 0.0), |(sin, cos), onset| {
+            let angle = TAU * onset.time / period;
+            let weight = weight(onset) as f64;
+            (sin + weight * angle.sin(), cos + weight * angle.cos())
+        });
+        let beat = f64::atan2(sin, cos).rem_euclid(TAU) / TAU * period;
+        Some(Tempo { bpm, beat })
+    }
+
+    /// How well the intervals between the onsets fit beats `period` seconds
+    /// apart. Intervals that span fewer beats count more, so a tempo doesn't
+    /// win just by being twice as fast as the music.
+    fn score(&self, period: f64) -> f32 {
+        let mut score = 0.0;
+        for (i, first) in self.onsets.iter().enumerate() {
+            for second in self.onsets.iter().skip(i + 1) {
+                let interval = second.time - first.time;
+                if interval > MAX_INTERVAL {
+                    break;
+                }
+                let beats = (interval / period).round().max(1.0);
+                let error = (interval - beats * period) / TOLERANCE;
+                let fit = (-0.5 * error * error).exp();
+                score += (fit / beats) as f32 * weight(first) * weight(second);
+            }
+        }
+        score
+    }
+}
+
+/// How much `onset` counts, stronger ones count more.
+fn weight(onset: &Onset) -> f32 {
+    0.5 + onset.strength
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Onsets on every beat at `bpm` starting at `first`, with every
+    /// `skip`th one missing and each one a little early or late.
+    fn beats(bpm: f64, first: f64, count: usize, skip: usize) -> Vec<Onset> {
+        let rng = fastrand::Rng::with_seed(3);
+        (0..count)
+            .filter(|n| skip == 0 || n % skip != skip - 1)
+            .map(|n| Onset {
+                time: first + n as f64 * 60.0 / bpm + (rng.f64() - 0.5) * 0.01,
+                strength: rng.f32(),
+            })
+            .collect()
+    }
+
+    fn track(onsets: &[Onset]) -> Option<Tempo> {
+        let mut tracker = TempoTracker::new();
+        for &onset in onsets {
+            tracker.push(onset);
+        }
+        tracker.tempo()
+    }
+
+    #[test]
+    fn needs_a_few_onsets() {
+        assert!(track(&beats(120.0, 0.0, MIN_ONSETS - 1, 0)).is_none());
+        assert!(track(&beats(120.0, 0.0, MIN_ONSETS, 0)).is_some());
+    }
+
+    #[test]
+    fn finds_the_tempo() {
+        for bpm in [75.0, 96.0, 120.0, 128.0, 150.0, 174.0] {
+            let tempo = track(&beats(bpm, 0.3, 24, 0)).unwrap();
+            assert!((tempo.bpm as f64 - bpm).abs() <= 1.0, "{} instead of {}", tempo.bpm, bpm);
+        }
+    }
+
+    #[test]
+    fn missing_beats_do_not_halve_the_tempo() {
+        let tempo = track(&beats(120.0, 0.0, 32, 3)).unwrap();
+        assert!((tempo.bpm - 120.0).abs() <= 1.0, "{}", tempo.bpm);
+    }
+
+    #[test]
+    fn phase_is_zero_on_the_beat() {
+        let tempo = track(&beats(100.0, 0.25, 24, 0)).unwrap();
+        let period = tempo.period();
+        for n in [24.0, 30.0, 100.0] {
+            let on_beat = 0.25 + n * 0.6;
+            let phase = tempo.phase(on_beat);
+            let off = phase.min(1.0 - phase) as f64 * period;
+            assert!(off < 0.02, "{} seconds off at beat {}", off, n);
+            let halfway = tempo.phase(on_beat + period / 2.0);
+            assert!((halfway - 0.5).abs() < 0.05, "{}", halfway);
+        }
+    }
+
+    #[test]
+    fn forgets_old_onsets() {
+        let mut tracker = TempoTracker::new();
+        for onset in beats(90.0, 0.0, 24, 0) {
+            tracker.push(onset);
+        }
+        let start = 24.0 * 60.0 / 90.0;
+        for onset in beats(140.0, start, 40, 0) {
+            tracker.push(onset);
+        }
+        assert!((tracker.tempo().unwrap().bpm - 140.0).abs() <= 1.0);
+    }
+}
@@ -1,9 +1,69 @@
 use ggez::{graphics::Color};
+use std::ops::Range;
 
+/// How the colors between texel centers are found.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    /// The texel the sample point falls in.
+    #[default]
+    Nearest,
+    /// Blend of the four texels around the sample point.
+    Bilinear,
+}
+
+/// What is sampled outside of 0..1.
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Address {
+    /// The edge texels carry on outwards.
+    #[default]
+    Clamp,
+    /// The texture tiles.
+    Repeat,
+    /// The texture tiles, flipped every other time.
+    Mirror,
+    /// Everything outside is this color.
+    Border(Color),
+}
+
+impl Address {
+    /// Index of the texel to use for texel `i` along an axis `size` texels
+    /// long, or `None` for the border.
+    fn resolve(self, i: isize, size: usize) -> Option<usize> {
+        let size = size as isize;
+        if size == 0 {
+            return None;
+        }
+        match self {
+            Address::Clamp => Some(i.clamp(0, size - 1) as usize),
+            Address::Repeat => Some(i.rem_euclid(size) as usize),
+            Address::Mirror => {
+                let i = i.rem_euclid(2 * size);
+                Some(if i < size { i } else { 2 * size - 1 - i } as usize)
+            }
+            Address::Border(_) => (0..size).contains(&i).then_some(i as usize),
+        }
+    }
+}
+
+#[derive(Default)]
 pub struct Texture {
     width: usize,
     height: usize,
     buffer: Vec<Color>,
+    filter: Filter,
+    address: Address,
+    mipmapped: bool,
+    /// Versions of the texture halved along each axis on its own, so a long
+    /// thin one can shrink along its length and keep its height. Level
+    /// (i, j) is `width >> i` by `height >> j`, stored row by row and
+    /// without (0, 0), which is the texture itself. Empty until
+    /// `update_mipmaps` is called.
+    mips: Vec<Texture>,
+    /// Number of levels along x and y, counting the texture itself.
+    levels: (usize, usize),
+    /// Whether the texture changed since the mips were made.
+    dirty: bool,
 }
 
 impl Texture {
@@ -13,6 +73,87 @@ impl Texture {
             width,
             height,
             buffer,
+            filter: Filter::default(),
+            address: Address::default(),
+            mipmapped: false,
+            mips: Vec::new(),
+            levels: (1, 1),
+            dirty: true,
+        }
+    }
+
+    pub fn with_filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn with_address(mut self, address: Address) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Makes the texture keep a mip chain, so it averages out instead of
+    /// aliasing when it is drawn much smaller than it is.
+    pub fn with_mipmaps(mut self) -> Self {
+        self.mipmapped = true;
+        self
+    }
+
+    /// Rebuilds the mip chain if the texture changed since the last call.
+    /// Until then, sampling ignores the mips. The levels are made the first
+    /// time and redrawn in place after that.
+    pub fn update_mipmaps(&mut self) {
+        if !self.mipmapped || !self.dirty {
+            return;
+        }
+        if self.mips.is_empty() {
+            self.levels = (mip_levels(self.width), mip_levels(self.height));
+            self.mips = (0..self.levels.1)
+                .flat_map(|j| (0..self.levels.0).map(move |i| (i, j)))
+                .skip(1)
+                .map(|(i, j)| {
+                    let (width, height) = ((self.width >> i).max(1), (self.height >> j).max(1));
+                    Texture::new(width, height).with_filter(self.filter).with_address(self.address)
+                })
+                .collect();
+        }
+        let mut mips = std::mem::take(&mut self.mips);
+        for n in 0..mips.len() {
+            let (i, j) = ((n + 1) % self.levels.0, (n + 1) / self.levels.0);
+            // each level is made from the one before it along x, or along y
+            // for the first of each row
+            let (done, rest) = mips.split_at_mut(n);
+            let (source, along_x) = if i == 0 { ((0, j - 1), false) } else { ((i - 1, j), true) };
+            let source = match source {
+                (0, 0) => &*self,
+                (i, j) => &done[j * self.levels.0 + i - 1],
+            };
+            source.halve_into(&mut rest[0], along_x);
+        }
+        self.mips = mips;
+        self.dirty = false;
+    }
+
+    fn level(&self, i: usize, j: usize) -> &Texture {
+        if (i, j) == (0, 0) {
+            self
+        } else {
+            &self.mips[j * self.levels.0 + i - 1]
+        }
+    }
+
+    /// Box filters the texture into `half`, which is half as wide or half
+    /// as high, rounded down but at least 1.
+    fn halve_into(&self, half: &mut Texture, along_x: bool) {
+        for y in 0..half.height {
+            for x in 0..half.width {
+                let (a, b) = if along_x {
+                    (self.get_color(2 * x, y), self.get_color((2 * x + 1).min(self.width - 1), y))
+                } else {
+                    (self.get_color(x, 2 * y), self.get_color(x, (2 * y + 1).min(self.height - 1)))
+                };
+                half.buffer[x + half.width * y] = lerp(a, b, 0.5);
+            }
         }
     }
 
@@ -26,6 +167,7 @@ impl Texture {
 
     pub fn set_color(&mut self, x: usize, y: usize, c: Color) {
         self.buffer[x + self.width * y] = c;
+        self.dirty = true;
     }
 
     pub fn get_color(&self, x: usize, y: usize) -> Color {
@@ -33,29 +175,320 @@ impl Texture {
     }
 
     pub fn clear(&mut self) {
-        for i in 0..self.width * self.height {
-            self.buffer[i] = Color::WHITE;
+        self.fill(Color::WHITE);
+    }
+
+    pub fn fill(&mut self, c: Color) {
+        self.buffer.fill(c);
+        self.dirty = true;
+    }
+
+    /// Moves every column `n` steps to the left. The leftmost columns wrap
+    /// around to the right edge.
+    pub fn scroll_left(&mut self, n: usize) {
+        for row in self.buffer.chunks_mut(self.width) {
+            row.rotate_left(n % self.width);
         }
+        self.dirty = true;
+    }
+
+    /// All of the texture's rows, to draw into.
+    pub fn rows_mut(&mut self) -> Rows<'_> {
+        self.dirty = true;
+        Rows { width: self.width, first: 0, buffer: &mut self.buffer }
     }
 
+    /// Splits the texture into bands of `rows` rows from the top down, the
+    /// last one shorter if `rows` doesn't divide the height, which can be
+    /// drawn into at the same time.
+    pub fn bands_mut(&mut self, rows: usize) -> impl Iterator<Item = Rows<'_>> {
+        self.dirty = true;
+        let width = self.width;
+        let rows = rows.max(1);
+        self.buffer
+            .chunks_mut((width * rows).max(1))
+            .enumerate()
+            .map(move |(i, buffer)| Rows { width, first: i * rows, buffer })
+    }
+
+    /// Packs the texture into opaque 8-bit RGBA bytes, row by row, the way
+    /// it looks when drawn over black.
+    pub fn to_rgba8(&self) -> Vec<u8> {
+        self.buffer
+            .iter()
+            .flat_map(|c| {
+                let (r, g, b, _) = Color::new(c.r * c.a, c.g * c.a, c.b * c.a, 1.0).to_rgba();
+                [r, g, b, 255]
+            })
+            .collect()
+    }
+
+    #[allow(dead_code)]
     pub fn sample_color(&self, x: f32, y: f32) -> Color {
-        let sx = (self.width as f32 * x) as usize;
-        let sy = (self.height as f32 * y) as usize;
-        if sx > self.width || sy > self.height {
-            Color::BLACK
-        } else {
-            self.buffer[(sx + self.width * sy).clamp(0,self.buffer.len()-1)]
+        self.sample_level(x, y)
+    }
+
+    /// Samples the texture at `x`, `y` and scales the color by `w`.
+    /// `footprint` is how much of the texture one screen pixel covers along
+    /// x and y, which decides the mip level.
+    pub fn sample_color_weighted(&self, x: f32, y: f32, footprint: (f32, f32), w: f32) -> Color {
+        let base = self.sample_mipmapped(x, y, footprint);
+        Color::from((base.r * w, base.g * w, base.b * w, base.a * w))
+    }
+
+    fn sample_mipmapped(&self, x: f32, y: f32, footprint: (f32, f32)) -> Color {
+        if self.dirty || self.mips.is_empty() {
+            return self.sample_level(x, y);
         }
+        // the level where a screen pixel covers about one texel, which is
+        // log2(texels) rounded
+        let lod = |texels: f32, levels: usize| {
+            ((texels * std::f32::consts::SQRT_2) as usize).max(1).ilog2().min(levels as u32 - 1) as usize
+        };
+        let i = lod(footprint.0 * self.width as f32, self.levels.0);
+        let j = lod(footprint.1 * self.height as f32, self.levels.1);
+        self.level(i, j).sample_level(x, y)
     }
-    
-    pub fn sample_color_weighted(&self, x: f32, y: f32, w: f32) -> Color {
-        let sx = (self.width as f32 * x) as usize;
-        let sy = (self.height as f32 * y) as usize;
-        if sx > self.width || sy > self.height {
-            Color::BLACK
-        } else {
-            let base = self.buffer[(sx + self.width * sy).clamp(0,self.buffer.len()-1)];
-            Color::from((base.r * w, base.g * w, base.b * w, base.a * w))
+
+    /// Samples this level alone, with its own filter.
+    fn sample_level(&self, x: f32, y: f32) -> Color {
+        let (fx, fy) = (self.width as f32 * x, self.height as f32 * y);
+        match self.filter {
+            Filter::Nearest => self.texel(fx.floor() as isize, fy.floor() as isize),
+            Filter::Bilinear => {
+                // texel centers sit at half steps
+                let (fx, fy) = (fx - 0.5, fy - 0.5);
+                let (x0, y0) = (fx.floor(), fy.floor());
+                let (tx, ty) = (fx - x0, fy - y0);
+                let (x0, y0) = (x0 as isize, y0 as isize);
+                let top = lerp(self.texel(x0, y0), self.texel(x0 + 1, y0), tx);
+                let bottom = lerp(self.texel(x0, y0 + 1), self.texel(x0 + 1, y0 + 1), tx);
+                lerp(top, bottom, ty)
+            }
+        }
+    }
+
+    /// Texel `x`, `y`, which may lie outside the texture.
+    fn texel(&self, x: isize, y: isize) -> Color {
+        match (self.address.resolve(x, self.width), self.address.resolve(y, self.height)) {
+            (Some(x), Some(y)) => self.buffer[x + self.width * y],
+            _ => match self.address {
+                Address::Border(c) => c,
+                _ => Color::BLACK,
+            },
+        }
+    }
+}
+
+/// Some rows of a texture, borrowed apart from the others.
+pub struct Rows<'a> {
+    width: usize,
+    /// Row of the texture the first one here is.
+    first: usize,
+    buffer: &'a mut [Color],
+}
+
+impl Rows<'_> {
+    /// The rows of the texture these are.
+    pub fn range(&self) -> Range<usize> {
+        let height = self.buffer.len().checked_div(self.width).unwrap_or(0);
+        self.first..self.first + height
+    }
+
+    /// Sets the texel at `x` and row `y` of the whole texture, which has to
+    /// be one of these rows.
+    pub fn set_color(&mut self, x: usize, y: usize, c: Color) {
+        self.buffer[x + self.width * (y - self.first)] = c;
+    }
+}
+
+/// Number of times `size` can be halved before reaching 1, plus one.
+fn mip_levels(size: usize) -> usize {
+    (usize::BITS - size.max(1).leading_zeros()) as usize
+}
+
+pub fn lerp(a: Color, b: Color, t: f32) -> Color {
+    Color::new(
+        a.r + (b.r - a.r) * t,
+        a.g + (b.g - a.g) * t,
+        a.b + (b.b - a.b) * t,
+        a.a + (b.a - a.a) * t,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Texture whose texel at x, y has red x / 10 and green y / 10.
+    fn numbered(width: usize, height: usize) -> Texture {
+        let mut t = Texture::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                t.set_color(x, y, texel(x, y));
+            }
+        }
+        t
+    }
+
+    fn texel(x: usize, y: usize) -> Color {
+        Color::new(x as f32 / 10., y as f32 / 10., 0., 1.)
+    }
+
+    fn assert_close(actual: Color, expected: Color) {
+        let close = [
+            (actual.r, expected.r),
+            (actual.g, expected.g),
+            (actual.b, expected.b),
+            (actual.a, expected.a),
+        ]
+        .iter()
+        .all(|(a, e)| (a - e).abs() < 1e-5);
+        assert!(close, "{:?} != {:?}", actual, expected);
+    }
+
+    /// Center of texel `i` of `size`, in 0..1.
+    fn center(i: f32, size: usize) -> f32 {
+        (i + 0.5) / size as f32
+    }
+
+    #[test]
+    fn nearest_hits_every_texel_of_a_non_square_texture() {
+        let t = numbered(5, 3);
+        for y in 0..3 {
+            for x in 0..5 {
+                assert_close(t.sample_color(center(x as f32, 5), center(y as f32, 3)), texel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn right_edge_stays_on_its_row() {
+        let t = numbered(4, 3);
+        assert_close(t.sample_color(1.0, center(0., 3)), texel(3, 0));
+        assert_close(t.sample_color(1.0, 1.0), texel(3, 2));
+    }
+
+    #[test]
+    fn clamp_stretches_the_edges() {
+        let t = numbered(4, 2);
+        assert_close(t.sample_color(-0.3, center(1., 2)), texel(0, 1));
+        assert_close(t.sample_color(-1.0, -1.0), texel(0, 0));
+        assert_close(t.sample_color(2.5, center(0., 2)), texel(3, 0));
+        assert_close(t.sample_color(center(2., 4), 7.0), texel(2, 1));
+    }
+
+    #[test]
+    fn repeat_tiles() {
+        let t = numbered(4, 2).with_address(Address::Repeat);
+        assert_close(t.sample_color(1. + center(1., 4), center(0., 2)), texel(1, 0));
+        assert_close(t.sample_color(center(3., 4) - 1., center(1., 2)), texel(3, 1));
+        assert_close(t.sample_color(center(0., 4) - 3., center(1., 2) + 2.), texel(0, 1));
+    }
+
+    #[test]
+    fn mirror_flips_every_other_tile() {
+        let t = numbered(4, 2).with_address(Address::Mirror);
+        assert_close(t.sample_color(1. + center(0., 4), center(0., 2)), texel(3, 0));
+        assert_close(t.sample_color(-center(0., 4), center(0., 2)), texel(0, 0));
+        assert_close(t.sample_color(-center(3., 4), center(1., 2)), texel(3, 1));
+        assert_close(t.sample_color(2. + center(1., 4), center(1., 2)), texel(1, 1));
+    }
+
+    #[test]
+    fn border_is_outside_only() {
+        let t = numbered(4, 2).with_address(Address::Border(Color::MAGENTA));
+        assert_close(t.sample_color(-0.01, 0.5), Color::MAGENTA);
+        assert_close(t.sample_color(1.0, 0.5), Color::MAGENTA);
+        assert_close(t.sample_color(0.5, -3.0), Color::MAGENTA);
+        assert_close(t.sample_color(0.0, 0.0), texel(0, 0));
+        assert_close(t.sample_color(0.999, 0.999), texel(3, 1));
+    }
+
+    #[test]
+    fn bilinear_blends_between_centers() {
+        let t = numbered(4, 2).with_filter(Filter::Bilinear);
+        assert_close(t.sample_color(center(2., 4), center(1., 2)), texel(2, 1));
+        assert_close(t.sample_color(0.5, 0.5), Color::new(0.15, 0.05, 0., 1.));
+        assert_close(t.sample_color(center(1.25, 4), center(0., 2)), Color::new(0.125, 0., 0., 1.));
+    }
+
+    #[test]
+    fn bilinear_edges_follow_the_address_mode() {
+        let clamp = numbered(4, 2).with_filter(Filter::Bilinear);
+        assert_close(clamp.sample_color(0.0, center(0., 2)), texel(0, 0));
+        assert_close(clamp.sample_color(1.0, center(1., 2)), texel(3, 1));
+
+        let repeat = numbered(4, 2).with_filter(Filter::Bilinear).with_address(Address::Repeat);
+        // halfway between the last and the first column
+        assert_close(repeat.sample_color(1.0, center(0., 2)), Color::new(0.15, 0., 0., 1.));
+
+        let border = numbered(4, 2)
+            .with_filter(Filter::Bilinear)
+            .with_address(Address::Border(Color::new(0., 0., 0., 0.)));
+        assert_close(border.sample_color(0.0, center(0., 2)), Color::new(0., 0., 0., 0.5));
+    }
+
+    #[test]
+    fn mipmaps_average_when_shrunk() {
+        let mut t = numbered(4, 2).with_mipmaps();
+        t.update_mipmaps();
+        // one pixel covers the whole texture
+        let average = Color::new(0.15, 0.05, 0., 1.);
+        assert_close(t.sample_color_weighted(0.3, 0.7, (1., 1.), 1.), average);
+        // only shrunk along x, the rows stay apart
+        assert_close(t.sample_color_weighted(0.3, center(0., 2), (1., 0.5), 1.), Color::new(0.15, 0., 0., 1.));
+        assert_close(t.sample_color_weighted(0.3, center(1., 2), (1., 0.5), 1.), Color::new(0.15, 0.1, 0., 1.));
+        // not shrunk at all
+        assert_close(t.sample_color_weighted(center(1., 4), center(1., 2), (0.25, 0.5), 1.), texel(1, 1));
+    }
+
+    #[test]
+    fn changed_texture_ignores_stale_mipmaps() {
+        let mut t = numbered(4, 2).with_mipmaps();
+        t.update_mipmaps();
+        t.fill(Color::RED);
+        assert_close(t.sample_color_weighted(0.5, 0.5, (1., 1.), 1.), Color::RED);
+        t.update_mipmaps();
+        assert_close(t.sample_color_weighted(0.5, 0.5, (1., 1.), 1.), Color::RED);
+    }
+
+    #[test]
+    fn mipmaps_are_redrawn_in_place() {
+        let mut t = numbered(8, 4).with_mipmaps();
+        t.update_mipmaps();
+        let buffers: Vec<_> = t.mips.iter().map(|mip| mip.buffer.as_ptr()).collect();
+        t.fill(Color::BLUE);
+        t.update_mipmaps();
+        assert_eq!(buffers, t.mips.iter().map(|mip| mip.buffer.as_ptr()).collect::<Vec<_>>());
+        let sizes: Vec<_> = t.mips.iter().map(|mip| (mip.width, mip.height)).collect();
+        assert_eq!(sizes[..4], [(4, 4), (2, 4), (1, 4), (8, 2)]);
+        for mip in &t.mips {
+            assert!(mip.buffer.iter().all(|&c| c == Color::BLUE));
+        }
+    }
+
+    #[test]
+    fn weight_scales_every_channel() {
+        let t = numbered(4, 2);
+        let c = t.sample_color_weighted(center(2., 4), center(1., 2), (0., 0.), 0.5);
+        assert_close(c, Color::new(0.1, 0.05, 0., 0.5));
+    }
+
+    #[test]
+    fn bands_cover_every_row_once() {
+        let mut t = Texture::new(3, 7);
+        t.fill(Color::BLACK);
+        let ranges: Vec<_> = t.bands_mut(3).map(|rows| rows.range()).collect();
+        assert_eq!(ranges, [0..3, 3..6, 6..7]);
+        for mut rows in t.bands_mut(3) {
+            for y in rows.range() {
+                rows.set_color(2, y, texel(2, y));
+            }
+        }
+        for y in 0..7 {
+            assert_close(t.get_color(2, y), texel(2, y));
+            assert_close(t.get_color(1, y), Color::BLACK);
         }
     }
 }
@@ -0,0 +1,539 @@
+//! Textures for each kind of wall, redrawn from the audio every frame.
+
+use crate::{
+    colormap::ColorMap,
+    map::WallKind,
+    mix::VisFrame,
+    spectrum::{self, Spectrum},
+    texture::{self, Address, Filter, Texture},
+};
+use ggez::graphics::Color;
+use ringbuf::{HeapRb, Rb};
+
+/// A `HeapRb` rather than a `LocalRb` so the `Game` holding it can be
+/// shared with the render threads.
+pub type WaveBuffer = HeapRb<VisFrame>;
+
+const SPECTRUM_SIZE: usize = 2048;
+/// Frequency bands shown by the spectrum walls.
+const BANDS: usize = 128;
+/// Columns of spectrogram history, one is added per update.
+const SPECTROGRAM_LENGTH: usize = 512;
+/// Quietest level shown by the VU meters and tint, in dB.
+const METER_FLOOR_DB: f32 = -60.0;
+/// Step by which the spectrum's dB range is moved.
+const DB_STEP: f32 = 6.0;
+/// Height of the spectrum bars and VU meter textures.
+const METER_HEIGHT: usize = 32;
+/// Lit segments of a VU bar, each followed by a dark gap.
+const VU_SEGMENTS: usize = METER_HEIGHT / 2;
+const VU_OFF: Color = Color::new(0.15, 0.15, 0.15, 1.0);
+const TINT: Color = Color::new(0.9, 0.2, 0.6, 1.0);
+/// Size of the floor and ceiling textures, each covering one tile.
+const TILE_SIZE: usize = 64;
+const CEILING: Color = Color::new(0.05, 0.05, 0.12, 1.0);
+const CEILING_FLASH: Color = Color::new(0.6, 0.4, 1.0, 1.0);
+/// How much of the ceiling's pulse is left after each update.
+const PULSE_DECAY: f32 = 0.85;
+
+/// What the `#` walls show.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum WaveMode {
+    /// The samples themselves.
+    #[default]
+    Waveform,
+    /// Spectrum as bars on a log frequency scale.
+    Bars,
+    /// Spectrum as colors on a log frequency scale.
+    HeatMap,
+    /// Spectrum over time, scrolling to the left with low frequencies at
+    /// the bottom.
+    Spectrogram,
+}
+
+impl WaveMode {
+    pub fn next(self) -> Self {
+        match self {
+            WaveMode::Waveform => WaveMode::Bars,
+            WaveMode::Bars => WaveMode::HeatMap,
+            WaveMode::HeatMap => WaveMode::Spectrogram,
+            WaveMode::Spectrogram => WaveMode::Waveform,
+        }
+    }
+}
+
+/// How waveforms are drawn.
+#[derive(Debug, Clone, Copy)]
+pub struct WaveStyle {
+    /// Thickness of the line, in texels.
+    pub thickness: f32,
+    /// Whether to blend the edges of the line into the background.
+    pub antialias: bool,
+    /// Whether to fill in everything between the line and the middle.
+    pub fill: bool,
+    /// Factor the samples are multiplied with, 1 makes a full scale sample
+    /// reach the edge.
+    pub scale: f32,
+    pub foreground: Color,
+    pub background: Color,
+}
+
+impl Default for WaveStyle {
+    fn default() -> Self {
+        Self {
+            thickness: 1.5,
+            antialias: true,
+            fill: false,
+            scale: 1.0,
+            foreground: Color::BLACK,
+            background: Color::WHITE,
+        }
+    }
+}
+
+/// How the walls are drawn.
+#[derive(Debug, Clone, Copy)]
+pub struct Settings {
+    pub mode: WaveMode,
+    pub colormap: ColorMap,
+    /// Range of levels shown by the spectrum views, in dB.
+    pub min_db: f32,
+    pub max_db: f32,
+    pub wave: WaveStyle,
+    /// Size of the waveform textures, in texels.
+    pub texture_width: usize,
+    pub texture_height: usize,
+    /// How much audio the waveforms show, in milliseconds.
+    pub window_ms: f32,
+}
+
+pub struct Walls {
+    settings: Settings,
+    waveform: Texture,
+    spectrogram: Texture,
+    spectrum: Texture,
+    vu: Texture,
+    tint: Texture,
+    channels: Texture,
+    floor: Texture,
+    ceiling: Texture,
+    analyzer: Spectrum,
+    /// Frames of audio the waveforms show.
+    window: usize,
+    /// The mono signal of the frames being drawn.
+    mono: Vec<f32>,
+    left: Vec<f32>,
+    right: Vec<f32>,
+    /// Level of each frequency band, in 0..1.
+    bands: Vec<f32>,
+    /// Slowly following average of the volume, in 0..1.
+    average_level: f32,
+    /// Brightness of the ceiling, kicked up whenever the volume jumps.
+    pulse: f32,
+}
+
+impl Walls {
+    pub fn new(settings: Settings) -> Self {
+        // smoothed so the long textures don't shimmer far away, the VU
+        // meters and tint stay sharp
+        let smooth = |width, height| Texture::new(width, height).with_filter(Filter::Bilinear).with_mipmaps();
+        let (width, height) = (settings.texture_width, settings.texture_height);
+        let mut vu = Texture::new(5, METER_HEIGHT);
+        vu.fill(Color::BLACK);
+        let mut spectrogram = smooth(SPECTROGRAM_LENGTH, BANDS);
+        spectrogram.fill(settings.colormap.color(0.0));
+        Self {
+            settings,
+            waveform: smooth(width, height),
+            spectrogram,
+            spectrum: smooth(BANDS, METER_HEIGHT),
+            vu,
+            tint: Texture::new(1, 1),
+            channels: smooth(width, height),
+            floor: smooth(TILE_SIZE, TILE_SIZE).with_address(Address::Repeat),
+            ceiling: smooth(TILE_SIZE, TILE_SIZE).with_address(Address::Repeat),
+            analyzer: Spectrum::new(SPECTRUM_SIZE),
+            window: 1,
+            mono: Vec::new(),
+            left: Vec::new(),
+            right: Vec::new(),
+            bands: vec![0.0; BANDS],
+            average_level: 0.0,
+            pulse: 0.0,
+        }
+    }
+
+    /// Converts the time window of the waveforms to frames of audio
+    /// arriving at `sample_rate`.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.window = ((self.settings.window_ms / 1000.0 * sample_rate as f32) as usize).max(1);
+    }
+
+    /// Frames of audio `update` wants to see, which can be more than the
+    /// waveforms show so the spectrum has enough to work with.
+    pub fn history(&self) -> usize {
+        self.window.max(SPECTRUM_SIZE)
+    }
+
+    /// Switches what the `#` walls show.
+    pub fn cycle_mode(&mut self) -> WaveMode {
+        self.settings.mode = self.settings.mode.next();
+        self.settings.mode
+    }
+
+    pub fn cycle_colormap(&mut self) -> ColorMap {
+        self.settings.colormap = self.settings.colormap.next();
+        self.settings.colormap
+    }
+
+    /// Moves the bottom of the spectrum's dB range up or down a step,
+    /// returning the new range. It stays at least a step below the top, or
+    /// where it is if it's closer already.
+    pub fn shift_min_db(&mut self, up: bool) -> (f32, f32) {
+        let s = &mut self.settings;
+        s.min_db = if up { (s.min_db + DB_STEP).min(s.max_db - DB_STEP).max(s.min_db) } else { s.min_db - DB_STEP };
+        (s.min_db, s.max_db)
+    }
+
+    /// Moves the top of the spectrum's dB range up or down a step,
+    /// returning the new range. It stays at least a step above the bottom,
+    /// or where it is if it's closer already.
+    pub fn shift_max_db(&mut self, up: bool) -> (f32, f32) {
+        let s = &mut self.settings;
+        s.max_db = if up { s.max_db + DB_STEP } else { (s.max_db - DB_STEP).max(s.min_db + DB_STEP).min(s.max_db) };
+        (s.min_db, s.max_db)
+    }
+
+    pub fn texture(&self, kind: WallKind) -> &Texture {
+        match kind {
+            WallKind::Waveform if self.settings.mode == WaveMode::Spectrogram => &self.spectrogram,
+            WallKind::Waveform => &self.waveform,
+            WallKind::Spectrum => &self.spectrum,
+            WallKind::Vu => &self.vu,
+            WallKind::Tint => &self.tint,
+            WallKind::Channels => &self.channels,
+        }
+    }
+
+    /// Lights the ceiling up by `strength`, from 0 to 1.
+    pub fn flash(&mut self, strength: f32) {
+        self.pulse = self.pulse.max(strength);
+    }
+
+    pub fn floor(&self) -> &Texture {
+        &self.floor
+    }
+
+    pub fn ceiling(&self) -> &Texture {
+        &self.ceiling
+    }
+
+    /// Redraws every texture from the frames in `frames`.
+    pub fn update(&mut self, frames: &WaveBuffer) {
+        self.mono.clear();
+        self.mono.extend(frames.iter().map(|f| f.mono));
+        self.left.clear();
+        self.left.extend(frames.iter().map(|f| f.left));
+        self.right.clear();
+        self.right.extend(frames.iter().map(|f| f.right));
+        // the most recent frames, the rest are only there for the spectrum
+        let shown = self.mono.len().saturating_sub(self.window)..self.mono.len();
+
+        let Settings { mode, colormap, min_db, max_db, wave, .. } = self.settings;
+        spectrum::log_bands(self.analyzer.compute(&self.mono), &mut self.bands);
+        self.bands.iter_mut().for_each(|b| *b = level(*b, min_db, max_db));
+
+        // one new column of history on the right, low frequencies at the bottom
+        self.spectrogram.scroll_left(1);
+        let last = self.spectrogram.width() - 1;
+        for (band, &value) in self.bands.iter().enumerate() {
+            self.spectrogram.set_color(last, BANDS - 1 - band, colormap.color(value));
+        }
+
+        match mode {
+            WaveMode::Waveform => {
+                self.waveform.fill(wave.background);
+                let height = self.waveform.height();
+                draw_wave(&mut self.waveform, &self.mono[shown.clone()], 0, height, &wave);
+            }
+            WaveMode::Bars => draw_bars(&mut self.waveform, &self.bands),
+            WaveMode::HeatMap => draw_heat_map(&mut self.waveform, &self.bands, colormap),
+            WaveMode::Spectrogram => {}
+        }
+
+        // left channel on the top half, right channel on the bottom
+        self.channels.fill(wave.background);
+        let half = self.channels.height() / 2;
+        draw_wave(&mut self.channels, &self.left[shown.clone()], 0, half, &wave);
+        draw_wave(&mut self.channels, &self.right[shown.clone()], half, half, &wave);
+
+        draw_bars(&mut self.spectrum, &self.bands);
+
+        let left = level(rms(self.left[shown.clone()].iter().copied()), METER_FLOOR_DB, 0.0);
+        let right = level(rms(self.right[shown.clone()].iter().copied()), METER_FLOOR_DB, 0.0);
+        draw_vu_bar(&mut self.vu, 1, left);
+        draw_vu_bar(&mut self.vu, 3, right);
+
+        let volume = level(rms(self.mono[shown].iter().copied()), METER_FLOOR_DB, 0.0);
+        let brightness = 0.2 + 0.8 * volume;
+        self.tint.set_color(
+            0,
+            0,
+            Color::new(TINT.r * brightness, TINT.g * brightness, TINT.b * brightness, 1.0),
+        );
+
+        // spectrum in rings around the middle of each floor tile, bass inside
+        for y in 0..TILE_SIZE {
+            for x in 0..TILE_SIZE {
+                let dx = (x as f32 + 0.5) / TILE_SIZE as f32 - 0.5;
+                let dy = (y as f32 + 0.5) / TILE_SIZE as f32 - 0.5;
+                let radius = (dx * dx + dy * dy).sqrt() * 2.0;
+                let band = ((radius * BANDS as f32) as usize).min(BANDS - 1);
+                self.floor.set_color(x, y, colormap.color(self.bands[band]));
+            }
+        }
+
+        let jump = ((volume - self.average_level) * 4.0).clamp(0.0, 1.0);
+        self.average_level += (volume - self.average_level) * 0.1;
+        self.pulse = (self.pulse * PULSE_DECAY).max(jump);
+        draw_ceiling(&mut self.ceiling, self.pulse);
+
+        for texture in [
+            &mut self.waveform,
+            &mut self.spectrogram,
+            &mut self.spectrum,
+            &mut self.channels,
+            &mut self.floor,
+            &mut self.ceiling,
+        ] {
+            texture.update_mipmaps();
+        }
+    }
+}
+
+/// Draws `samples` as a line through rows `top..top + rows` of `texture`,
+/// stretched or squeezed to fit its width.
+fn draw_wave(texture: &mut Texture, samples: &[f32], top: usize, rows: usize, style: &WaveStyle) {
+    let Some(last) = samples.len().checked_sub(1) else { return };
+    // position of a sample's texel center, -1 on the top row and 1 on the bottom
+    let mul = (rows - 1) as f32 / 2.;
+    let position = |sample: f32| ((sample * style.scale).clamp(-1., 1.) + 1.) * mul + 0.5;
+    // the signal in between samples, which sit at whole numbers
+    let at = |t: f32| {
+        let i = (t as usize).min(last);
+        samples[i] + (samples[(i + 1).min(last)] - samples[i]) * (t - i as f32)
+    };
+    let middle = rows as f32 / 2.;
+    let per_column = samples.len() as f32 / texture.width() as f32;
+    for x in 0..texture.width() {
+        // each column reaches halfway to the samples of its neighbours, so
+        // the line stays connected however fast it moves
+        let start = (x as f32 * per_column - 0.5).clamp(0., last as f32);
+        let end = ((x + 1) as f32 * per_column - 0.5).clamp(0., last as f32);
+        let (mut low, mut high) = (at(start).min(at(end)), at(start).max(at(end)));
+        for &sample in &samples[start.ceil() as usize..=end.floor() as usize] {
+            (low, high) = (low.min(sample), high.max(sample));
+        }
+        let (mut low, mut high) = (position(low), position(high));
+        if style.fill {
+            (low, high) = (low.min(middle), high.max(middle));
+        }
+        let (low, high) = (low - style.thickness / 2., high + style.thickness / 2.);
+        for y in low.floor().max(0.) as usize..(high.ceil().min(rows as f32)) as usize {
+            let coverage = (high.min(y as f32 + 1.) - low.max(y as f32)).clamp(0., 1.);
+            let coverage = match (style.antialias, coverage >= 0.5) {
+                (true, _) => coverage,
+                (false, true) => 1.,
+                (false, false) => continue,
+            };
+            texture.set_color(x, top + y, texture::lerp(style.background, style.foreground, coverage));
+        }
+    }
+}
+
+fn rms(samples: impl Iterator<Item = f32>) -> f32 {
+    let (sum, n) = samples.fold((0.0, 0), |(sum, n), s| (sum + s * s, n + 1));
+    (sum / n.max(1) as f32).sqrt()
+}
+
+/// Maps an amplitude to 0..1 on a dB scale from `min_db` to `max_db`.
+fn level(amplitude: f32, min_db: f32, max_db: f32) -> f32 {
+    let db = 20.0 * amplitude.max(1e-9).log10();
+    ((db - min_db) / (max_db - min_db)).clamp(0.0, 1.0)
+}
+
+/// Draws `levels` as black bars rising from the bottom, stretched across the
+/// width of `texture`.
+fn draw_bars(texture: &mut Texture, levels: &[f32]) {
+    let (width, height) = (texture.width(), texture.height());
+    texture.clear();
+    for x in 0..width {
+        let bar = (levels[x * levels.len() / width] * height as f32) as usize;
+        for y in height - bar..height {
+            texture.set_color(x, y, Color::BLACK);
+        }
+    }
+}
+
+/// Fills `texture` with a column per level, colored by `colormap`.
+fn draw_heat_map(texture: &mut Texture, levels: &[f32], colormap: ColorMap) {
+    let (width, height) = (texture.width(), texture.height());
+    for x in 0..width {
+        let color = colormap.color(levels[x * levels.len() / width]);
+        for y in 0..height {
+            texture.set_color(x, y, color);
+        }
+    }
+}
+
+/// Fills the ceiling tile, with its edges lighting up by `pulse`.
+fn draw_ceiling(texture: &mut Texture, pulse: f32) {
+    let mix = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let edge = Color::new(
+        mix(CEILING.r, CEILING_FLASH.r, pulse),
+        mix(CEILING.g, CEILING_FLASH.g, pulse),
+        mix(CEILING.b, CEILING_FLASH.b, pulse),
+        1.0,
+    );
+    let inside = Color::new(
+        mix(CEILING.r, CEILING_FLASH.r, pulse * 0.3),
+        mix(CEILING.g, CEILING_FLASH.g, pulse * 0.3),
+        mix(CEILING.b, CEILING_FLASH.b, pulse * 0.3),
+        1.0,
+    );
+    let (width, height) = (texture.width(), texture.height());
+    for y in 0..height {
+        for x in 0..width {
+            let on_edge = x < 2 || y < 2 || x >= width - 2 || y >= height - 2;
+            texture.set_color(x, y, if on_edge { edge } else { inside });
+        }
+    }
+}
+
+/// Draws a segmented meter in column `x`, green at the bottom to red at the top.
+fn draw_vu_bar(texture: &mut Texture, x: usize, level: f32) {
+    let lit = (level * VU_SEGMENTS as f32).round() as usize;
+    for segment in 0..VU_SEGMENTS {
+        let y = METER_HEIGHT - 2 - segment * 2;
+        let color = if segment >= lit {
+            VU_OFF
+        } else if segment >= VU_SEGMENTS * 7 / 8 {
+            Color::RED
+        } else if segment >= VU_SEGMENTS * 5 / 8 {
+            Color::YELLOW
+        } else {
+            Color::GREEN
+        };
+        texture.set_color(x, y, color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(min_db: f32, max_db: f32) -> Settings {
+        Settings {
+            mode: WaveMode::Waveform,
+            colormap: ColorMap::default(),
+            min_db,
+            max_db,
+            wave: WaveStyle::default(),
+            texture_width: 64,
+            texture_height: 16,
+            window_ms: 10.0,
+        }
+    }
+
+    /// Draws `samples` into the 9 rows below the top one of a red texture
+    /// `width` texels wide, so anything drawn outside of them shows, and
+    /// returns the texture.
+    fn wave(samples: &[f32], width: usize, thickness: f32, antialias: bool, fill: bool) -> Texture {
+        let mut texture = Texture::new(width, 11);
+        texture.fill(Color::RED);
+        let style = WaveStyle { thickness, antialias, fill, ..WaveStyle::default() };
+        draw_wave(&mut texture, samples, 1, 9, &style);
+        texture
+    }
+
+    /// The colors of column `x` from the top of the texture down.
+    fn column(texture: &Texture, x: usize) -> Vec<Color> {
+        (0..texture.height()).map(|y| texture.get_color(x, y)).collect()
+    }
+
+    const R: Color = Color::RED;
+    const B: Color = Color::BLACK;
+
+    #[test]
+    fn silence_is_a_line_through_the_middle() {
+        let t = wave(&[0.0; 8], 4, 1.0, false, false);
+        for x in 0..4 {
+            assert_eq!(column(&t, x), [R, R, R, R, R, B, R, R, R, R, R]);
+        }
+    }
+
+    #[test]
+    fn antialiasing_blends_the_partly_covered_edges() {
+        // 1.5 texels thick around the middle of row 4 covers a quarter of
+        // the rows on either side
+        let gray = Color::new(0.75, 0.75, 0.75, 1.0);
+        assert_eq!(column(&wave(&[0.0; 4], 4, 1.5, true, false), 0), [R, R, R, R, gray, B, gray, R, R, R, R]);
+        assert_eq!(column(&wave(&[0.0; 4], 4, 1.5, false, false), 0), [R, R, R, R, R, B, R, R, R, R, R]);
+        // a line on the border between rows 4 and 5 covers half of each
+        let half = Color::new(0.5, 0.5, 0.5, 1.0);
+        assert_eq!(column(&wave(&[0.125; 4], 4, 1.0, true, false), 0), [R, R, R, R, R, half, half, R, R, R, R]);
+        assert_eq!(column(&wave(&[0.125; 4], 4, 1.0, false, false), 0), [R, R, R, R, R, B, B, R, R, R, R]);
+    }
+
+    #[test]
+    fn fill_reaches_the_middle() {
+        assert_eq!(column(&wave(&[1.0; 4], 4, 1.0, false, true), 2), [R, R, R, R, R, B, B, B, B, B, R]);
+        assert_eq!(column(&wave(&[-1.0; 4], 4, 1.0, false, true), 2), [R, B, B, B, B, B, R, R, R, R, R]);
+        assert_eq!(column(&wave(&[1.0; 4], 4, 1.0, false, false), 2), [R, R, R, R, R, R, R, R, R, B, R]);
+        // clipped samples don't spill out of the rows
+        assert_eq!(column(&wave(&[4.0; 4], 4, 3.0, true, true), 2)[10], R);
+    }
+
+    #[test]
+    fn steep_lines_stay_connected() {
+        let t = wave(&[-1.0, 1.0], 2, 1.0, false, false);
+        for y in 1..10 {
+            assert!(t.get_color(0, y) == B || t.get_color(1, y) == B, "gap in row {}", y);
+        }
+    }
+
+    #[test]
+    fn db_range_never_turns_around() {
+        for (min_db, max_db) in [(-72.0, 0.0), (-3.0, 0.0), (-20.0, -19.0)] {
+            let mut walls = Walls::new(settings(min_db, max_db));
+            for step in 0..40 {
+                let (min_db, max_db) = match step % 4 {
+                    0 => walls.shift_min_db(true),
+                    1 => walls.shift_max_db(false),
+                    2 => walls.shift_max_db(false),
+                    _ => walls.shift_min_db(true),
+                };
+                assert!(min_db < max_db, "{} >= {} after step {}", min_db, max_db, step);
+            }
+        }
+    }
+
+    #[test]
+    fn db_shifts_move_the_way_they_are_told() {
+        let mut walls = Walls::new(settings(-3.0, 0.0));
+        // already closer than a step, so neither end moves towards the other
+        assert_eq!(walls.shift_max_db(false), (-3.0, 0.0));
+        assert_eq!(walls.shift_min_db(true), (-3.0, 0.0));
+        assert_eq!(walls.shift_min_db(false), (-3.0 - DB_STEP, 0.0));
+        assert_eq!(walls.shift_max_db(true), (-3.0 - DB_STEP, DB_STEP));
+    }
+
+    #[test]
+    fn level_maps_the_db_range_to_0_1() {
+        assert_eq!(level(1.0, -60.0, 0.0), 1.0);
+        assert!(level(0.001, -60.0, 0.0).abs() < 1e-5);
+        assert!((level(0.031_622_78, -60.0, 0.0) - 0.5).abs() < 1e-5);
+        assert_eq!(level(0.0, -60.0, 0.0), 0.0);
+        assert_eq!(level(10.0, -60.0, 0.0), 1.0);
+    }
+}